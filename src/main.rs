@@ -7,84 +7,633 @@ use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
 use std::time::{Duration, SystemTime};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     select,
+    time::Instant,
+};
+use wire::{
+    encode_frame, split_frame, AddrFormat, ControlFrame, FrameFormat, FrameHeader, UdpPacketWithSource,
+    ENCODED_ADDR_LEN, FRAME_TYPE_CONTROL, FRAME_TYPE_DATA, HELLO_CLIENT_TOKEN,
+    HELLO_FLOW_CLOSE, HELLO_FLOW_DIAGNOSTICS, HELLO_GOODBYE, HELLO_IPV4_ONLY, HELLO_KEEPALIVE_ECHO, HELLO_NO_SOURCE_HEADER, HELLO_SHORT_LENGTH, HELLO_TYPED_FRAMES,
 };
 
-/// UDP packet structure that preserves original source address information
-/// when tunneling through TCP connections. This enables proper return packet routing.
-#[derive(Debug, Clone)]
-struct UdpPacketWithSource {
-    source: SocketAddr,
-    data: Vec<u8>,
+mod netflow;
+mod wire;
+
+/// Byte stream carrying the tunnel: a TCP connection, or an in-memory pipe with --loopback.
+trait Transport: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Transport for T {}
+
+/// Runs a TCP write under the optional --write-timeout. Expiry is reported as a TimedOut error
+/// so a peer that stopped reading is treated like a broken connection instead of stalling the loop.
+async fn with_write_timeout<F>(limit: Option<Duration>, write: F) -> std::io::Result<()>
+where
+    F: std::future::Future<Output = std::io::Result<()>>,
+{
+    let Some(limit) = limit else {
+        return write.await;
+    };
+    match tokio::time::timeout(limit, write).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("write timed out after {}s, peer is not reading", limit.as_secs()),
+        )),
+    }
+}
+
+/// Reads how many bytes are still queued, unsent, in a TCP connection's kernel send buffer. The
+/// --tx-high-water/--tx-low-water backpressure watches this instead of keeping its own queue.
+#[derive(Clone, Copy)]
+struct SendQueueProbe {
+    #[cfg(target_os = "linux")]
+    fd: std::os::fd::RawFd,
+    /// Size of the send buffer (SO_SNDBUF) when the connection was set up, if known.
+    capacity: Option<usize>,
 }
 
-impl UdpPacketWithSource {
-    /// Serializes the UDP packet with source address metadata for TCP transmission.
-    /// Format: [port:2][ip:16][data:N] where IP is always 16 bytes (IPv4 mapped to IPv6).
-    fn serialize(&self) -> Vec<u8> {
-        let mut result = Vec::new();
+impl SendQueueProbe {
+    #[cfg(target_os = "linux")]
+    fn new(stream: &tokio::net::TcpStream) -> Self {
+        let fd = std::os::fd::AsRawFd::as_raw_fd(stream);
+        let mut sndbuf: libc::c_int = 0;
+        let mut optlen = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: the fd is a live socket and the option buffer matches its declared length
+        let ret = unsafe {
+            libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, (&mut sndbuf as *mut libc::c_int).cast(), &mut optlen)
+        };
+        Self { fd, capacity: (ret == 0).then_some(sndbuf as usize) }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new(_stream: &tokio::net::TcpStream) -> Self {
+        Self { capacity: None }
+    }
+
+    /// Unsent bytes (SIOCOUTQ), or None where the platform cannot tell.
+    #[cfg(target_os = "linux")]
+    fn queued(&self) -> Option<usize> {
+        let mut queued: libc::c_int = 0;
+        // SAFETY: the fd belongs to the connection this probe was created for, which outlives it
+        let ret = unsafe { libc::ioctl(self.fd, libc::TIOCOUTQ, &mut queued) };
+        (ret == 0).then_some(queued as usize)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn queued(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// How often the TCP send queue is rechecked while UDP receive is paused for backpressure.
+const BACKPRESSURE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// A TCP send queue above this percentage of its capacity (--tx-high-water, else the send buffer
+/// size) for SEND_QUEUE_WARN_AFTER is reported as a warning.
+const SEND_QUEUE_NEAR_FULL_PERCENT: usize = 90;
+const SEND_QUEUE_WARN_AFTER: Duration = Duration::from_secs(5);
+
+/// Free space the TCP receive buffer should have before each read. Bytes of a partial frame stay
+/// where they are between reads and are only moved to the front once less than this is left.
+const TCP_BUF_MIN_SPARE: usize = 16 * 1024;
+
+/// Delay before the connect side reconnects after the listen side sent a Goodbye, since a peer
+/// that is shutting down on purpose is unlikely to be back within the usual retry delay.
+const PEER_SHUTDOWN_RECONNECT_DELAY: Duration = Duration::from_secs(30);
 
-        // Source port as little-endian 16-bit integer
-        result.extend_from_slice(&self.source.port().to_le_bytes());
+/// Longest a shutdown waits for the Goodbye frame to reach the kernel before closing anyway.
+const GOODBYE_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
 
-        // Source IP address normalized to 16 bytes (IPv6 format)
-        match self.source.ip() {
-            IpAddr::V4(ipv4) => {
-                // IPv4-mapped IPv6 format: ::ffff:a.b.c.d
-                result.extend_from_slice(&[0u8; 10]);     // 10 zero bytes
-                result.extend_from_slice(&[0xff, 0xff]);  // IPv4-mapped prefix
-                result.extend_from_slice(&ipv4.octets()); // 4 bytes of IPv4 address
+/// How often the keepalive round-trip time is logged at info level (--keepalive-interval).
+/// Every sample is also logged at debug level.
+const KEEPALIVE_RTT_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Logs a hex dump of one frame (header and body as they appear on the stream) for --dump-wire.
+/// `limit` is the number of bytes shown; 0 disables dumping.
+fn dump_frame(limit: usize, direction: &str, header: &[u8], body: &[u8]) {
+    if limit == 0 {
+        return;
+    }
+    let total = header.len() + body.len();
+    let mut hex = String::with_capacity(limit.min(total) * 3);
+    for byte in header.iter().chain(body).take(limit) {
+        use std::fmt::Write;
+        let _ = write!(hex, "{byte:02x} ");
+    }
+    let elided = if total > limit { format!(" ... ({} more)", total - limit) } else { String::new() };
+    tracing::info!("[WIRE] {} {} bytes: {}{}", direction, total, hex.trim_end(), elided);
+}
+
+/// Writes a single frame of the given type to the TCP stream and flushes it.
+async fn write_frame<S: AsyncWrite + Unpin>(
+    tcp_stream: &mut S,
+    format: FrameFormat,
+    frame_type: u8,
+    body: &[u8],
+    dump_bytes: usize,
+) -> std::io::Result<()> {
+    let mut header = Vec::with_capacity(format.header_len());
+    FrameHeader::new(frame_type, body.len())
+        .encode(format, &mut header)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    dump_frame(dump_bytes, "tx", &header, body);
+    tcp_stream.write_all(&header).await?;
+    tcp_stream.write_all(body).await?;
+    tcp_stream.flush().await
+}
+
+/// Writes frames already encoded back to back (header and body each) to the TCP stream and flushes it.
+async fn write_encoded_frames<S: AsyncWrite + Unpin>(tcp_stream: &mut S, frames: &[u8]) -> std::io::Result<()> {
+    tcp_stream.write_all(frames).await?;
+    tcp_stream.flush().await
+}
+
+/// Writes a single data frame to the TCP stream and flushes it.
+async fn write_data_frame<S: AsyncWrite + Unpin>(
+    tcp_stream: &mut S,
+    format: FrameFormat,
+    serialized: &[u8],
+    dump_bytes: usize,
+) -> std::io::Result<()> {
+    write_frame(tcp_stream, format, FRAME_TYPE_DATA, serialized, dump_bytes).await
+}
+
+/// Writes a single control frame to the TCP stream and flushes it.
+async fn write_control_frame<S: AsyncWrite + Unpin>(
+    tcp_stream: &mut S,
+    format: FrameFormat,
+    frame: &ControlFrame,
+    dump_bytes: usize,
+) -> std::io::Result<()> {
+    write_frame(tcp_stream, format, FRAME_TYPE_CONTROL, &frame.serialize(), dump_bytes).await
+}
+
+/// Reads one whole frame outside the main loop (handshake, probe), returning its type and body.
+/// Frames larger than MAX_HANDSHAKE_FRAME_LEN are rejected before anything is allocated.
+async fn read_frame<S: AsyncRead + Unpin>(
+    tcp_stream: &mut S,
+    format: FrameFormat,
+    dump_bytes: usize,
+) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut header_bytes = vec![0; format.header_len()];
+    tcp_stream.read_exact(&mut header_bytes).await?;
+    let header = FrameHeader::decode(format, &header_bytes)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    if header.len > MAX_HANDSHAKE_FRAME_LEN {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "oversized frame"));
+    }
+    let mut body = vec![0; header.len];
+    tcp_stream.read_exact(&mut body).await?;
+    dump_frame(dump_bytes, "rx", &header_bytes, &body);
+    Ok((header.frame_type, body))
+}
+
+/// Wall-clock time of a flow activity timestamp, for the NetFlow exporter. Activity is tracked
+/// on the tokio clock so the idle sweep follows the runtime's notion of time.
+fn wall_clock(at: Instant) -> SystemTime {
+    SystemTime::now().checked_sub(at.elapsed()).unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+/// Flows without traffic for this long are removed and their sockets closed.
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(600); // 10 minutes
+
+/// Most flow sockets closed, and most idle flows evicted, per main loop iteration. Larger
+/// teardowns (a dropped connection with many flows, a mass expiry) are spread over several
+/// iterations so the loop keeps serving traffic in between.
+const FLOW_TEARDOWN_BATCH: usize = 256;
+
+/// Consecutive hard receive errors after which a flow socket is closed and replaced.
+const FLOW_SOCKET_MAX_ERRORS: u32 = 5;
+
+/// Default for --udp-rebind-after: consecutive hard receive errors after which the main UDP
+/// socket is replaced by a fresh one on the same address.
+const UDP_REBIND_AFTER_ERRORS: u32 = 10;
+
+/// First and longest delay between attempts to bind the --udp-bind port again when a rebind had
+/// to release it and could not take it back; the delay doubles after every failed attempt.
+const UDP_REBIND_RETRY_MIN: Duration = Duration::from_secs(1);
+const UDP_REBIND_RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// How long new flow sockets are not attempted after running out of file descriptors.
+const FD_EXHAUSTION_COOLDOWN: Duration = Duration::from_secs(5);
+
+/// Rough userspace cost of one flow (table entries and socket bookkeeping), counted against --max-memory.
+const FLOW_MEMORY_ESTIMATE: usize = 512;
+
+/// Minimum time between warnings about return packets on flow sockets without a reverse mapping.
+const UNMAPPED_RETURN_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the listen side keeps the flows of a dropped connection with a client token.
+const CLIENT_TOKEN_GRACE: Duration = Duration::from_secs(60);
+
+/// Default for --handshake-timeout: how long the whole Hello exchange may take on a new connection.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest frame accepted by `read_frame`: a maximum-size UDP datagram plus its address header.
+const MAX_HANDSHAKE_FRAME_LEN: usize = 65535 + ENCODED_ADDR_LEN;
+
+/// Sends our Hello and waits for the peer's, returning the peer's capability flags and client token.
+/// An endpoint only initiates the exchange when it has something to negotiate; the peer answers
+/// from its main loop. Frames the peer sent before its Hello are appended to `pending`, re-encoded
+/// in the negotiated framing, so the main loop still processes them, in order. Sending and
+/// receiving together are bounded by `timeout`, so a peer stalling at any point is dropped.
+async fn perform_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    tcp_stream: &mut S,
+    flags: u32,
+    token: &[u8],
+    timeout: Duration,
+    pending: &mut Vec<u8>,
+    dump_bytes: usize,
+) -> eyre::Result<(u32, Vec<u8>)> {
+    let mut early_frames = Vec::new();
+    let exchange = async {
+        let hello = ControlFrame::Hello { flags, token: token.to_vec() };
+        write_control_frame(tcp_stream, FrameFormat::Legacy, &hello, dump_bytes)
+            .await
+            .wrap_err("send hello")?;
+        loop {
+            let (frame_type, body) = read_frame(tcp_stream, FrameFormat::Legacy, dump_bytes)
+                .await
+                .wrap_err("receive peer hello")?;
+            if frame_type == FRAME_TYPE_CONTROL {
+                if let Ok(ControlFrame::Hello { flags, token }) = ControlFrame::deserialize(&body) {
+                    return Ok::<_, eyre::Report>((flags, token));
+                }
             }
-            IpAddr::V6(ipv6) => {
-                result.extend_from_slice(&ipv6.octets()); // Native 16-byte IPv6 address
+            early_frames.push((frame_type, body));
+        }
+    };
+
+    let (peer_flags, peer_token) = match tokio::time::timeout(timeout, exchange).await {
+        Ok(result) => result?,
+        Err(_) => eyre::bail!("handshake did not complete within {:?}", timeout),
+    };
+
+    let frame_format = FrameFormat::negotiate(flags, peer_flags);
+    for (frame_type, body) in early_frames {
+        if let Err(e) = encode_frame(frame_format, frame_type, &body, pending) {
+            tracing::warn!("dropping frame received before the peer's hello: {e}");
+        }
+    }
+    Ok((peer_flags, peer_token))
+}
+
+/// Per-step timeout for --probe: TCP connect, handshake and echo wait.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects to the relay, completes the Hello handshake and, with `echo`, checks that a marker
+/// datagram makes it through the tunnel to the backend and back (--probe / --probe-echo).
+/// Any failure is returned as an error, so the process exits non-zero.
+async fn run_probe(tcp_addr: SocketAddr, hello_flags: u32, echo: bool, dump_bytes: usize) -> eyre::Result<()> {
+    let started = std::time::Instant::now();
+    tracing::info!("probe: connecting to {:?}", tcp_addr);
+    let mut stream = tokio::time::timeout(PROBE_TIMEOUT, tokio::net::TcpStream::connect(tcp_addr))
+        .await
+        .map_err(|_| eyre::eyre!("tcp connect to {:?} timed out after {:?}", tcp_addr, PROBE_TIMEOUT))?
+        .wrap_err_with(|| format!("tcp connect to {:?}", tcp_addr))?;
+
+    // The handshake is always performed so the probe verifies the peer speaks the protocol
+    let mut pending = Vec::new();
+    let (peer_flags, _) = perform_handshake(&mut stream, hello_flags, &[], PROBE_TIMEOUT, &mut pending, dump_bytes)
+        .await
+        .wrap_err("handshake")?;
+    let (addr_format, frame_format) = negotiate_wire_format(hello_flags, peer_flags);
+    tracing::info!("probe: handshake completed in {:?}", started.elapsed());
+
+    if !echo {
+        tracing::info!("probe: OK");
+        return Ok(());
+    }
+
+    // Marker datagram with an unspecified source; the relay routes the backend's reply back to it
+    let nonce = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let marker = UdpPacketWithSource {
+        source: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        data: format!("udp-over-tcp probe {nonce}").into_bytes(),
+    };
+    let serialized = marker
+        .serialize(addr_format)
+        .expect("IPv4 probe source is representable in every format");
+    let sent_at = std::time::Instant::now();
+    write_data_frame(&mut stream, frame_format, &serialized, dump_bytes).await.wrap_err("send probe datagram")?;
+
+    let wait_echo = async {
+        loop {
+            let (frame_type, body) = read_frame(&mut stream, frame_format, dump_bytes).await?;
+            if frame_type != FRAME_TYPE_DATA {
+                continue;
+            }
+            if let Ok(packet) = UdpPacketWithSource::deserialize(&body, addr_format) {
+                if packet.data == marker.data {
+                    return Ok::<_, std::io::Error>(());
+                }
             }
         }
+    };
+    tokio::time::timeout(PROBE_TIMEOUT, wait_echo)
+        .await
+        .map_err(|_| eyre::eyre!("probe datagram was not echoed within {:?}", PROBE_TIMEOUT))?
+        .wrap_err("wait for probe echo")?;
+
+    tracing::info!("probe: OK, echo received after {:?}", sent_at.elapsed());
+    Ok(())
+}
+
+/// Chooses the data frame address format and the stream framing for a connection from both
+/// sides' Hello flags. Omitting the source header, the compact IPv4 format and typed frames are only
+/// used when both endpoints asked for them.
+fn negotiate_wire_format(local_flags: u32, peer_flags: u32) -> (AddrFormat, FrameFormat) {
+    let format = if local_flags & peer_flags & HELLO_NO_SOURCE_HEADER != 0 {
+        AddrFormat::Omitted
+    } else if local_flags & peer_flags & HELLO_IPV4_ONLY != 0 {
+        AddrFormat::Ipv4
+    } else {
+        AddrFormat::Full
+    };
+    let frame_format = FrameFormat::negotiate(local_flags, peer_flags);
+    tracing::info!("negotiated {:?} source address format and {:?} framing with peer (flags local {:#x}, peer {:#x})",
+        format, frame_format, local_flags, peer_flags);
+    (format, frame_format)
+}
+
+/// Lifetime totals, reported once as a shutdown summary when the process exits normally.
+/// Forward traffic flows toward the `--udp-sendto` backend (connect side UDP -> listen side UDP);
+/// return traffic flows back toward the original clients.
+#[derive(Debug, Default)]
+struct LifetimeStats {
+    forward_packets: u64,
+    forward_bytes: u64,
+    return_packets: u64,
+    return_bytes: u64,
+    total_flows: u64,
+    peak_flows: usize,
+    connections: u64,
+    backend_unreachable: u64,
+    paused_drops: u64,
+    sendto_denied: u64,
+    memory_drops: u64,
+    fd_exhaustion_drops: u64,
+    backpressure_pauses: u64,
+    peak_send_queue: usize,
+    prefix_drops: u64,
+    /// Keepalive round-trip times (--keepalive-interval): latest, lowest and highest sample
+    rtt_last: Option<Duration>,
+    rtt_min: Option<Duration>,
+    rtt_max: Option<Duration>,
+    rtt_samples: u64,
+}
+
+impl LifetimeStats {
+    fn record_forward(&mut self, bytes: usize) {
+        self.forward_packets += 1;
+        self.forward_bytes += bytes as u64;
+    }
+
+    fn record_return(&mut self, bytes: usize) {
+        self.return_packets += 1;
+        self.return_bytes += bytes as u64;
+    }
 
-        // Original UDP packet payload
-        result.extend_from_slice(&self.data);
-        result
+    fn record_rtt(&mut self, rtt: Duration) {
+        self.rtt_last = Some(rtt);
+        self.rtt_min = Some(self.rtt_min.map_or(rtt, |min| min.min(rtt)));
+        self.rtt_max = Some(self.rtt_max.map_or(rtt, |max| max.max(rtt)));
+        self.rtt_samples += 1;
     }
 
-    /// Deserializes a UDP packet with source address metadata from TCP stream.
-    /// Returns None if the data is malformed or too short (minimum 18 bytes required).
-    fn deserialize(data: &[u8]) -> Option<Self> {
-        if data.len() < 18 {
-            return None; // Need at least 2 bytes port + 16 bytes IP
+    /// Logs the keepalive round-trip time gauge, if any keepalive was echoed yet.
+    fn log_rtt(&self) {
+        if let (Some(last), Some(min), Some(max)) = (self.rtt_last, self.rtt_min, self.rtt_max) {
+            tracing::info!("Keepalive RTT {:?} (min {:?}, max {:?} over {} samples)", last, min, max, self.rtt_samples);
         }
+    }
 
-        // Extract source port from first 2 bytes (little-endian)
-        let port = u16::from_le_bytes([data[0], data[1]]);
+    /// Logs the consolidated shutdown summary.
+    fn log_summary(&self, uptime: Duration) {
+        tracing::info!("Shutdown summary: uptime {}s, forward {} packets / {} bytes, return {} packets / {} bytes, \
+            {} flows total, peak {} concurrent flows, {} reconnects, {} backend unreachable errors, {} dropped while paused, \
+            {} dropped by --sendto-allow, {} dropped under memory pressure, \
+            {} dropped while out of file descriptors, {} UDP receive pauses for backpressure, \
+            peak TCP send queue {} bytes, {} dropped by --match-prefix",
+            uptime.as_secs(), self.forward_packets, self.forward_bytes, self.return_packets, self.return_bytes,
+            self.total_flows, self.peak_flows, self.connections.saturating_sub(1), self.backend_unreachable,
+            self.paused_drops, self.sendto_denied, self.memory_drops, self.fd_exhaustion_drops,
+            self.backpressure_pauses, self.peak_send_queue, self.prefix_drops);
+        self.log_rtt();
+    }
+}
 
-        // Extract source IP from next 16 bytes
-        let ip_bytes = &data[2..18];
-        let ip = if ip_bytes[0..10] == [0u8; 10] && ip_bytes[10..12] == [0xff, 0xff] {
-            // IPv4-mapped IPv6 format detected
-            IpAddr::V4(Ipv4Addr::new(ip_bytes[12], ip_bytes[13], ip_bytes[14], ip_bytes[15]))
-        } else {
-            // Native IPv6 address
-            let mut ipv6_bytes = [0u8; 16];
-            ipv6_bytes.copy_from_slice(ip_bytes);
-            IpAddr::V6(std::net::Ipv6Addr::from(ipv6_bytes))
+/// Consecutive failures after which a pool destination is taken out of the rotation.
+const BACKEND_MAX_FAILURES: u32 = 3;
+
+/// How long an unhealthy pool destination gets no flows before it is tried again.
+const BACKEND_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Passive health tracking for the destinations of --udp-sendto-roundrobin. Send errors and ICMP
+/// port unreachable (see `report_unreachable`) count as failures, any datagram coming back from a
+/// destination as success.
+/// After BACKEND_MAX_FAILURES consecutive failures a destination is skipped for
+/// BACKEND_RETRY_INTERVAL; it then gets flows again on probation, where one more failure is enough
+/// to take it out again.
+#[derive(Debug, Default)]
+struct PoolHealth {
+    backends: Vec<(SocketAddr, BackendHealth)>,
+}
+
+#[derive(Debug, Default)]
+struct BackendHealth {
+    failures: u32,
+    unhealthy_until: Option<std::time::Instant>,
+    times_unhealthy: u64,
+}
+
+impl PoolHealth {
+    fn new(spec: &PortSpec) -> Self {
+        let backends = match spec {
+            PortSpec::Pool(pool) => pool.iter().map(|dest| (*dest, BackendHealth::default())).collect(),
+            _ => Vec::new(),
         };
+        Self { backends }
+    }
 
-        let source = SocketAddr::new(ip, port);
-        let packet_data = data[18..].to_vec(); // Remaining bytes are the UDP payload
+    fn get_mut(&mut self, dest: &SocketAddr) -> Option<&mut BackendHealth> {
+        self.backends.iter_mut().find(|(addr, _)| addr == dest).map(|(_, health)| health)
+    }
 
-        Some(UdpPacketWithSource {
-            source,
-            data: packet_data,
-        })
+    fn is_healthy(&self, dest: &SocketAddr) -> bool {
+        let now = std::time::Instant::now();
+        self.backends
+            .iter()
+            .find(|(addr, _)| addr == dest)
+            .map_or(true, |(_, health)| health.unhealthy_until.map_or(true, |until| now >= until))
+    }
+
+    fn record_failure(&mut self, dest: &SocketAddr) {
+        let Some(health) = self.get_mut(dest) else {
+            return;
+        };
+        health.failures += 1;
+        if health.failures >= BACKEND_MAX_FAILURES {
+            health.unhealthy_until = Some(std::time::Instant::now() + BACKEND_RETRY_INTERVAL);
+            health.times_unhealthy += 1;
+            tracing::warn!("pool destination {} unhealthy after {} consecutive failures, retrying in {:?}",
+                dest, health.failures, BACKEND_RETRY_INTERVAL);
+        }
+    }
+
+    fn record_success(&mut self, dest: &SocketAddr) {
+        let Some(health) = self.get_mut(dest) else {
+            return;
+        };
+        if health.unhealthy_until.take().is_some() {
+            tracing::info!("pool destination {} is answering again", dest);
+        }
+        health.failures = 0;
+    }
+
+    /// Logs the health of every destination, for the shutdown summary.
+    fn log_summary(&self) {
+        let now = std::time::Instant::now();
+        for (dest, health) in &self.backends {
+            let state = if health.unhealthy_until.is_some_and(|until| now < until) { "unhealthy" } else { "healthy" };
+            tracing::info!("Pool destination {}: {}, {} consecutive failures, marked unhealthy {} times",
+                dest, state, health.failures, health.times_unhealthy);
+        }
+    }
+}
+
+/// --log-file rotates once the file grows past this size.
+const LOG_FILE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Number of rotated log files kept next to the active one (PATH.1 is the newest).
+const LOG_FILE_KEEP: usize = 3;
+
+/// Log file for --log-file with size-based rotation: once the file exceeds `max_bytes` it is
+/// renamed to PATH.1 (older files shift up to PATH.`keep`) and a fresh file is started.
+/// tracing_appender::rolling is not used because it only rotates on time boundaries and dates its
+/// file names, while --log-file promises a bounded amount of disk under fixed names.
+struct RotatingLogFile {
+    path: std::path::PathBuf,
+    file: std::fs::File,
+    written: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl RotatingLogFile {
+    fn open(path: std::path::PathBuf, max_bytes: u64, keep: usize) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(RotatingLogFile { path, file, written, max_bytes, keep })
+    }
+
+    fn rotated_path(&self, index: usize) -> std::path::PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{index}"));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for index in (1..self.keep).rev() {
+            // Gaps in the numbering are fine, the missing files simply are not shifted
+            let _ = std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+        }
+        std::fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl std::io::Write for RotatingLogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            if let Err(e) = self.rotate() {
+                // Keep logging to the current file; rotation is retried after another `max_bytes`
+                eprintln!("failed to rotate log file {}: {e}", self.path.display());
+            }
+            self.written = 0;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Source of pause/resume requests for maintenance windows: SIGUSR2 on Unix, nothing elsewhere.
+/// While paused, traffic is dropped but the TCP connection and flow state are kept.
+struct PauseToggle {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl PauseToggle {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            let signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2())
+                .map_err(|e| tracing::warn!("failed to install SIGUSR2 handler, pause/resume unavailable: {e}"))
+                .ok();
+            PauseToggle { signal }
+        }
+        #[cfg(not(unix))]
+        {
+            PauseToggle {}
+        }
+    }
+
+    /// Resolves on the next toggle request.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        {
+            if let Some(signal) = &mut self.signal {
+                signal.recv().await;
+                return;
+            }
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+/// Resolves when the process is asked to stop (Ctrl-C, or SIGTERM on Unix).
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(e) => {
+                tracing::warn!("failed to install SIGTERM handler: {e}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
     }
 }
 
 /// Port specification for UDP binding and forwarding.
 /// Fixed: Use a specific socket address.
 /// Auto: Enable dynamic per-flow socket management with the specified IP.
+/// Pool: Assign each new flow one of several fixed destinations (forwarding only).
 #[derive(Debug, Clone)]
 enum PortSpec {
     Fixed(SocketAddr),
     Auto(IpAddr),
+    Pool(Vec<SocketAddr>),
 }
 
 impl PortSpec {
@@ -93,6 +642,33 @@ impl PortSpec {
     }
 }
 
+/// An IP network in CIDR notation, used by --sendto-allow.
+#[derive(Debug, Clone, Copy)]
+struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Whether `ip` lies inside this network. Addresses of the other family never match.
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The runtime is single-threaded on purpose: one task owns every socket and flow table, so
+/// nothing may block it. Addresses are parsed as literal IPs (no name resolution), and the only
+/// synchronous I/O left is log output (stderr or --log-file).
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> eyre::Result<()> {
     // Initialize logging based on command-line flags before argument parsing
@@ -101,43 +677,131 @@ async fn main() -> eyre::Result<()> {
         std::env::set_var("RUST_LOG", "debug");
     } else if args.contains(&"-v".to_string()) || args.contains(&"--verbose".to_string()) {
         std::env::set_var("RUST_LOG", "info");
+    } else if args.contains(&"-q".to_string()) || args.contains(&"--quiet".to_string()) {
+        // Also overrides a RUST_LOG inherited from the environment
+        std::env::set_var("RUST_LOG", "error");
     }
 
-    tracing_subscriber::fmt()
-        .with_writer(std::io::stderr)
-        .with_env_filter(tracing_subscriber::filter::EnvFilter::from_default_env())
-        .init();
+    // --log-file is also applied here, so that everything after startup goes to the file
+    let log_file = args.iter().enumerate().find_map(|(i, arg)| match arg.strip_prefix("--log-file") {
+        Some("") => args.get(i + 1).cloned(),
+        Some(value) => value.strip_prefix('=').map(String::from),
+        None => None,
+    });
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::filter::EnvFilter::from_default_env());
+    if let Some(path) = &log_file {
+        let file = RotatingLogFile::open(path.into(), LOG_FILE_MAX_BYTES, LOG_FILE_KEEP).wrap_err_with(|| format!("--log-file: open {path}"))?;
+        subscriber
+            .with_writer(std::sync::Mutex::new(file))
+            .with_ansi(false)
+            .init();
+    } else {
+        subscriber.with_writer(std::io::stderr).init();
+    }
 
     let mut listen = false;
+    let mut loopback = false;
     let mut tcp_addr = None;
+    let mut extra_tcp_listen = Vec::new();
+    let mut return_tcp_listen = None;
+    let mut return_tcp_connect = None;
     let mut udp_bind = None;
     let mut udp_sendto = None;
     let mut verbose = false;
     let mut debug = false;
+    let mut quiet = false;
+    let mut tcp_listen_backlog = None;
+    let mut flow_diagnostics = false;
+    let mut sticky_flows = false;
+    let mut forward_only = false;
+    let mut return_only = false;
+    let mut shard_offset = None;
+    let mut shard_len = None;
+    let mut ipv4_only = false;
+    let mut typed_frames = false;
+    let mut short_frame_len = false;
+    let mut no_source_header = false;
+    let mut client_token = Vec::new();
+    let mut flow_port_range = None;
+    let mut prewarm_payload = None;
+    let mut sendto_allow = Vec::new();
+    let mut routes = Vec::new();
+    let mut match_prefixes = Vec::new();
+    let mut netflow = None;
+    let mut sendto_port_offset = 0i32;
+    let mut write_timeout = None;
+    let mut handshake_timeout = HANDSHAKE_TIMEOUT;
+    let mut keepalive_interval = None;
+    let mut user = None;
+    let mut group = None;
+    let mut udp_batch_recv = 1usize;
+    let mut udp_rebind_after = UDP_REBIND_AFTER_ERRORS;
+    let mut new_flow_threshold = 1u64;
+    let mut sendto_source_port = None;
+    let mut max_memory = None;
+    let mut tx_high_water = None;
+    let mut tx_low_water = None;
+    let mut dump_wire = false;
+    let mut dump_wire_bytes = 64usize;
+    let mut reject_new_connections = false;
+    let mut probe = false;
+    let mut probe_echo = false;
 
     let mut parser = lexopt::Parser::from_env();
     while let Some(arg) = parser.next().wrap_err("parse arguments")? {
         match arg {
-            Long("tcp-listen") | Short('l') if tcp_addr.is_none() => {
-                listen = true;
+            Long("tcp-listen") | Short('l') if tcp_addr.is_none() || listen => {
+                let addr = parser
+                    .value()
+                    .wrap_err("value missing")
+                    .and_then(|v| port_or_addr(v, Ipv4Addr::UNSPECIFIED))
+                    .wrap_err("--tcp-listen")?;
+                // Repeated --tcp-listen adds listeners; the first one is the main address
+                if tcp_addr.is_some() {
+                    extra_tcp_listen.push(addr);
+                } else {
+                    listen = true;
+                    tcp_addr = Some(addr);
+                }
+            }
+            Long("tcp-connect") | Short('t') if tcp_addr.is_none() => {
+                listen = false;
                 tcp_addr = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(|v| port_or_addr(v, Ipv4Addr::LOCALHOST))
+                        .wrap_err("--tcp-connect")?,
+                );
+            }
+            Long("return-tcp-listen") => {
+                return_tcp_listen = Some(
                     parser
                         .value()
                         .wrap_err("value missing")
                         .and_then(|v| port_or_addr(v, Ipv4Addr::UNSPECIFIED))
-                        .wrap_err("--tcp-listen")?,
+                        .wrap_err("--return-tcp-listen")?,
                 );
             }
-            Long("tcp-connect") | Short('t') if tcp_addr.is_none() => {
-                listen = false;
-                tcp_addr = Some(
+            Long("return-tcp-connect") => {
+                return_tcp_connect = Some(
                     parser
                         .value()
                         .wrap_err("value missing")
                         .and_then(|v| port_or_addr(v, Ipv4Addr::LOCALHOST))
-                        .wrap_err("--tcp-connect")?,
+                        .wrap_err("--return-tcp-connect")?,
                 );
             }
+            Long("loopback") => {
+                loopback = true;
+            }
+            Long("forward-only") => {
+                forward_only = true;
+            }
+            Long("return-only") => {
+                return_only = true;
+            }
             Long("udp-bind") | Short('u') if udp_bind.is_none() => {
                 udp_bind = Some(
                     parser
@@ -156,12 +820,282 @@ async fn main() -> eyre::Result<()> {
                         .wrap_err("--udp-sendto")?,
                 );
             }
+            Long("udp-sendto-roundrobin") if udp_sendto.is_none() => {
+                udp_sendto = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(|v| parse_addr_list(v, Ipv4Addr::LOCALHOST))
+                        .map(PortSpec::Pool)
+                        .wrap_err("--udp-sendto-roundrobin")?,
+                );
+            }
+            Long("ipv4-only") => {
+                ipv4_only = true;
+            }
+            Long("typed-frames") => {
+                typed_frames = true;
+            }
+            Long("frame-len-width") => {
+                short_frame_len = parser
+                    .value()
+                    .wrap_err("value missing")
+                    .and_then(|v| match v.to_str() {
+                        Some("16") => Ok(true),
+                        Some("32") => Ok(false),
+                        _ => eyre::bail!("width must be 16 or 32"),
+                    })
+                    .wrap_err("--frame-len-width")?;
+            }
+            Long("client-token") => {
+                client_token = parser
+                    .value()
+                    .wrap_err("value missing")
+                    .and_then(|v| {
+                        let token = v.to_string_lossy().into_owned().into_bytes();
+                        if token.is_empty() || token.len() > 255 {
+                            eyre::bail!("token must be 1 to 255 bytes long");
+                        }
+                        Ok(token)
+                    })
+                    .wrap_err("--client-token")?;
+            }
+            Long("no-source-header") => {
+                no_source_header = true;
+            }
+            Long("sticky-flows") => {
+                sticky_flows = true;
+            }
+            Long("shard-offset") => {
+                shard_offset = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(|v| v.parse::<usize>().wrap_err("provided value is not a number"))
+                        .wrap_err("--shard-offset")?,
+                );
+            }
+            Long("shard-len") => {
+                shard_len = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(|v| v.parse::<usize>().wrap_err("provided value is not a number"))
+                        .wrap_err("--shard-len")?,
+                );
+            }
+            Long("tcp-listen-backlog") => {
+                tcp_listen_backlog = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(|v| v.parse::<u32>().wrap_err("provided value is not a number"))
+                        .wrap_err("--tcp-listen-backlog")?,
+                );
+            }
+            Long("flow-diagnostics") => {
+                flow_diagnostics = true;
+            }
+            Long("flow-port-range") => {
+                flow_port_range = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(parse_port_range)
+                        .wrap_err("--flow-port-range")?,
+                );
+            }
+            Long("prewarm-payload") => {
+                prewarm_payload = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(parse_hex)
+                        .wrap_err("--prewarm-payload")?,
+                );
+            }
+            Long("sendto-port-offset") => {
+                sendto_port_offset = parser
+                    .value()
+                    .wrap_err("value missing")
+                    .and_then(|v| v.parse::<i32>().wrap_err("provided value is not a number"))
+                    .and_then(|offset| {
+                        if offset.unsigned_abs() > u32::from(u16::MAX) {
+                            eyre::bail!("offset must be between -65535 and 65535");
+                        }
+                        Ok(offset)
+                    })
+                    .wrap_err("--sendto-port-offset")?;
+            }
+            Long("netflow") => {
+                netflow = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(|v| port_or_addr(v, Ipv4Addr::LOCALHOST))
+                        .wrap_err("--netflow")?,
+                );
+            }
+            Long("match-prefix") => {
+                match_prefixes.push(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(parse_hex)
+                        .and_then(|prefix| {
+                            if prefix.is_empty() {
+                                eyre::bail!("prefix must not be empty");
+                            }
+                            Ok(prefix)
+                        })
+                        .wrap_err("--match-prefix")?,
+                );
+            }
+            Long("route") => {
+                routes.push(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(parse_route)
+                        .wrap_err("--route")?,
+                );
+            }
+            Long("sendto-allow") => {
+                sendto_allow.push(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(parse_ip_net)
+                        .wrap_err("--sendto-allow")?,
+                );
+            }
+            Long("dump-wire") => {
+                dump_wire = true;
+            }
+            Long("dump-wire-bytes") => {
+                dump_wire_bytes = parser
+                    .value()
+                    .wrap_err("value missing")
+                    .and_then(|v| v.parse::<usize>().wrap_err("provided value is not a number"))
+                    .wrap_err("--dump-wire-bytes")?;
+            }
+            Long("max-memory") => {
+                max_memory = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(|v| v.parse::<usize>().wrap_err("provided value is not a number"))
+                        .wrap_err("--max-memory")?,
+                );
+            }
+            Long("tx-high-water") => {
+                tx_high_water = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(|v| v.parse::<usize>().wrap_err("provided value is not a number"))
+                        .wrap_err("--tx-high-water")?,
+                );
+            }
+            Long("tx-low-water") => {
+                tx_low_water = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(|v| v.parse::<usize>().wrap_err("provided value is not a number"))
+                        .wrap_err("--tx-low-water")?,
+                );
+            }
+            Long("udp-batch-recv") => {
+                udp_batch_recv = parser
+                    .value()
+                    .wrap_err("value missing")
+                    .and_then(|v| v.parse::<usize>().wrap_err("provided value is not a number"))
+                    .wrap_err("--udp-batch-recv")?;
+            }
+            Long("udp-sendto-source-port") => {
+                sendto_source_port = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(|v| v.parse::<u16>().wrap_err("provided value is not a port number"))
+                        .wrap_err("--udp-sendto-source-port")?,
+                );
+            }
+            Long("new-flow-threshold") => {
+                new_flow_threshold = parser
+                    .value()
+                    .wrap_err("value missing")
+                    .and_then(|v| v.parse::<u64>().wrap_err("provided value is not a number"))
+                    .wrap_err("--new-flow-threshold")?;
+            }
+            Long("udp-rebind-after") => {
+                udp_rebind_after = parser
+                    .value()
+                    .wrap_err("value missing")
+                    .and_then(|v| v.parse::<u32>().wrap_err("provided value is not a number"))
+                    .wrap_err("--udp-rebind-after")?;
+            }
+            Long("write-timeout") => {
+                write_timeout = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(|v| v.parse::<u64>().wrap_err("provided value is not a number"))
+                        .map(Duration::from_secs)
+                        .wrap_err("--write-timeout")?,
+                );
+            }
+            Long("handshake-timeout") => {
+                handshake_timeout = parser
+                    .value()
+                    .wrap_err("value missing")
+                    .and_then(|v| v.parse::<u64>().wrap_err("provided value is not a number"))
+                    .map(Duration::from_secs)
+                    .wrap_err("--handshake-timeout")?;
+            }
+            Long("keepalive-interval") => {
+                keepalive_interval = Some(
+                    parser
+                        .value()
+                        .wrap_err("value missing")
+                        .and_then(|v| v.parse::<u64>().wrap_err("provided value is not a number"))
+                        .map(Duration::from_secs)
+                        .wrap_err("--keepalive-interval")?,
+                );
+            }
+            Long("user") => {
+                user = Some(parser.value().wrap_err("value missing").and_then(|v| {
+                    v.into_string().map_err(|_| eyre::eyre!("invalid user name"))
+                }).wrap_err("--user")?);
+            }
+            Long("group") => {
+                group = Some(parser.value().wrap_err("value missing").and_then(|v| {
+                    v.into_string().map_err(|_| eyre::eyre!("invalid group name"))
+                }).wrap_err("--group")?);
+            }
+            Long("reject-new-connections") => {
+                reject_new_connections = true;
+            }
+            Long("probe") => {
+                probe = true;
+            }
+            Long("probe-echo") => {
+                probe_echo = true;
+            }
             Short('v') | Long("verbose") => {
                 verbose = true;
             }
             Long("debug") => {
                 debug = true;
             }
+            Short('q') | Long("quiet") => {
+                quiet = true;
+            }
+            Long("log-file") => {
+                // Already applied when logging was initialized
+                parser.value().wrap_err("value missing").wrap_err("--log-file")?;
+            }
             Short('h') | Long("help") => {
                 usage(0);
             }
@@ -169,10 +1103,64 @@ async fn main() -> eyre::Result<()> {
         }
     }
 
-    let Some(tcp_addr) = tcp_addr else {
-        usage(1);
+    if quiet && (verbose || debug) {
+        eyre::bail!("--quiet cannot be combined with --verbose or --debug");
+    }
+    let tcp_addr = match (tcp_addr, loopback) {
+        (Some(_), true) => eyre::bail!("--loopback replaces --tcp-listen/--tcp-connect"),
+        // No TCP endpoint with --loopback; the address only shows up in log lines
+        (None, true) => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+        (Some(addr), false) => addr,
+        (None, false) => usage(1),
     };
-    let Some(udp_bind_spec) = udp_bind else {
+    if dump_wire {
+        if dump_wire_bytes == 0 {
+            eyre::bail!("--dump-wire-bytes must be at least 1");
+        }
+        tracing::warn!("--dump-wire is on: every frame is logged, expect high log volume");
+    }
+    let dump_wire_bytes = if dump_wire { dump_wire_bytes } else { 0 };
+
+    let mut hello_flags = 0;
+    if ipv4_only {
+        hello_flags |= HELLO_IPV4_ONLY;
+    }
+    if typed_frames {
+        hello_flags |= HELLO_TYPED_FRAMES;
+    }
+    if short_frame_len {
+        // 16-bit lengths need the type byte: legacy framing keeps the control flag in the length
+        hello_flags |= HELLO_TYPED_FRAMES | HELLO_SHORT_LENGTH;
+    }
+    if no_source_header {
+        hello_flags |= HELLO_NO_SOURCE_HEADER;
+    }
+    if !client_token.is_empty() {
+        hello_flags |= HELLO_CLIENT_TOKEN;
+    }
+    // The connect side asks for flow port reports, which peers that predate the Hello cannot read
+    if flow_diagnostics && !listen {
+        hello_flags |= HELLO_FLOW_DIAGNOSTICS;
+    }
+    // Keepalives need to know the peer echoes them, so they always start a Hello exchange. Any Hello
+    // we send also announces FlowClose, Goodbye and FlowPort support; without one the connection keeps
+    // the original framing, which peers that predate the Hello can read, and those frames are not sent.
+    if hello_flags != 0 || keepalive_interval.is_some() {
+        hello_flags |= HELLO_KEEPALIVE_ECHO | HELLO_FLOW_CLOSE | HELLO_GOODBYE | HELLO_FLOW_DIAGNOSTICS;
+    }
+
+    // Probe mode only needs the TCP side: check the relay and exit without forwarding
+    if probe_echo && !probe {
+        eyre::bail!("--probe-echo requires --probe");
+    }
+    if probe {
+        if listen || loopback {
+            eyre::bail!("--probe can only be used with --tcp-connect (connect side)");
+        }
+        return run_probe(tcp_addr, hello_flags, probe_echo, dump_wire_bytes).await;
+    }
+
+    let Some(mut udp_bind_spec) = udp_bind else {
         eyre::bail!("no udp port given");
     };
     let Some(udp_sendto_spec) = udp_sendto else {
@@ -190,52 +1178,491 @@ async fn main() -> eyre::Result<()> {
         _ => {}
     }
 
+    // The listen side forwards from its --udp-bind socket in fixed mode, so the source port is that
+    // socket's port: set it through the bind address, which must not name a different one
+    if let Some(port) = sendto_source_port {
+        let PortSpec::Fixed(bind_addr) = &mut udp_bind_spec else {
+            eyre::bail!("--udp-sendto-source-port requires a fixed --udp-bind (use --flow-port-range with auto)");
+        };
+        if !listen || loopback {
+            eyre::bail!("--udp-sendto-source-port can only be used with --tcp-listen (listen side)");
+        }
+        if port == 0 {
+            eyre::bail!("--udp-sendto-source-port must not be 0");
+        }
+        if bind_addr.port() != 0 && bind_addr.port() != port {
+            eyre::bail!("--udp-sendto-source-port {} conflicts with --udp-bind port {}: both set the port of the same socket \
+                (use --udp-bind {}:0 or the same port)", port, bind_addr.port(), bind_addr.ip());
+        }
+        bind_addr.set_port(port);
+    }
+
+    if loopback && (udp_bind_spec.is_auto() || udp_sendto_spec.is_auto()) {
+        eyre::bail!("--loopback requires a fixed --udp-bind and a fixed or pool --udp-sendto");
+    }
+    if loopback && !client_token.is_empty() {
+        eyre::bail!("--client-token cannot be used with --loopback");
+    }
+
+    if no_source_header && (udp_bind_spec.is_auto() || !matches!(udp_sendto_spec, PortSpec::Fixed(_))) {
+        eyre::bail!("--no-source-header requires a fixed --udp-bind and --udp-sendto (no auto mode or pools)");
+    }
+    if sticky_flows && !matches!(udp_sendto_spec, PortSpec::Pool(_)) {
+        eyre::bail!("--sticky-flows requires --udp-sendto-roundrobin");
+    }
+    let shard = match (shard_offset, shard_len) {
+        (None, None) => None,
+        (Some(offset), Some(len)) if len > 0 => Some((offset, len)),
+        (Some(_), Some(_)) => eyre::bail!("--shard-len must be at least 1"),
+        _ => eyre::bail!("--shard-offset and --shard-len must be given together"),
+    };
+    if shard.is_some() && !matches!(udp_sendto_spec, PortSpec::Pool(_)) {
+        eyre::bail!("--shard-offset/--shard-len require --udp-sendto-roundrobin");
+    }
+
+    if flow_diagnostics && listen && !udp_bind_spec.is_auto() {
+        eyre::bail!("--flow-diagnostics on the listen side requires --udp-bind auto");
+    }
+    if flow_port_range.is_some() && !(listen && udp_bind_spec.is_auto()) {
+        eyre::bail!("--flow-port-range can only be used with --tcp-listen and --udp-bind auto");
+    }
+    if !match_prefixes.is_empty() && listen {
+        eyre::bail!("--match-prefix can only be used with --tcp-connect (connect side)");
+    }
+    if !routes.is_empty() && !listen {
+        eyre::bail!("--route can only be used with --tcp-listen (listen side)");
+    }
+    if prewarm_payload.is_some() && !(listen && udp_bind_spec.is_auto()) {
+        eyre::bail!("--prewarm-payload can only be used with --tcp-listen and --udp-bind auto");
+    }
+    if sendto_port_offset != 0 && !udp_sendto_spec.is_auto() {
+        eyre::bail!("--sendto-port-offset requires --udp-sendto IP:auto");
+    }
+    if !sendto_allow.is_empty() && !udp_sendto_spec.is_auto() {
+        eyre::bail!("--sendto-allow requires --udp-sendto IP:auto");
+    }
+    if !(1..=MAX_UDP_BATCH_RECV).contains(&udp_batch_recv) {
+        eyre::bail!("--udp-batch-recv must be between 1 and {}", MAX_UDP_BATCH_RECV);
+    }
+    if new_flow_threshold == 0 {
+        eyre::bail!("--new-flow-threshold must be at least 1");
+    }
+    if udp_rebind_after == 0 {
+        eyre::bail!("--udp-rebind-after must be at least 1");
+    }
+    let tx_watermarks = match (tx_high_water, tx_low_water) {
+        (None, None) => None,
+        (None, Some(_)) => eyre::bail!("--tx-low-water requires --tx-high-water"),
+        (Some(high), low) => {
+            let low = low.unwrap_or(high / 2);
+            if high == 0 || low >= high {
+                eyre::bail!("--tx-low-water must be below --tx-high-water, which must be at least 1");
+            }
+            Some((high, low))
+        }
+    };
+    if tx_watermarks.is_some() && (loopback || !cfg!(target_os = "linux")) {
+        eyre::bail!("--tx-high-water needs a TCP connection on Linux (not available with --loopback)");
+    }
+    let run_as = if user.is_some() || group.is_some() {
+        if loopback {
+            eyre::bail!("--user/--group cannot be used with --loopback");
+        }
+        Some(resolve_run_as(user.as_deref(), group.as_deref())?)
+    } else {
+        None
+    };
+    if handshake_timeout.is_zero() {
+        eyre::bail!("--handshake-timeout must be at least 1 second");
+    }
+    if keepalive_interval == Some(Duration::ZERO) {
+        eyre::bail!("--keepalive-interval must be at least 1 second");
+    }
+    if write_timeout == Some(Duration::ZERO) {
+        eyre::bail!("--write-timeout must be at least 1 second");
+    }
+    if !client_token.is_empty() && listen {
+        eyre::bail!("--client-token can only be used with --tcp-connect (connect side)");
+    }
+    if reject_new_connections && !listen {
+        eyre::bail!("--reject-new-connections can only be used with --tcp-listen (listen side)");
+    }
+    if tcp_listen_backlog.is_some() && !listen {
+        eyre::bail!("--tcp-listen-backlog can only be used with --tcp-listen (listen side)");
+    }
+    if loopback && (return_tcp_listen.is_some() || return_tcp_connect.is_some()) {
+        eyre::bail!("--return-tcp-listen/--return-tcp-connect cannot be used with --loopback");
+    }
+    let return_tcp_addr = match (return_tcp_listen, return_tcp_connect) {
+        (Some(_), Some(_)) => eyre::bail!("--return-tcp-listen and --return-tcp-connect are mutually exclusive"),
+        (Some(_), None) if !listen => eyre::bail!("--return-tcp-listen can only be used with --tcp-listen (listen side)"),
+        (None, Some(_)) if listen => eyre::bail!("--return-tcp-connect can only be used with --tcp-connect (connect side)"),
+        (addr, None) | (None, addr) => addr,
+    };
+
+    // One-way tunnels: forward is connect side to listen side, return the opposite way
+    if forward_only && return_only {
+        eyre::bail!("--forward-only and --return-only are mutually exclusive");
+    }
+    if forward_only {
+        if udp_sendto_spec.is_auto() {
+            eyre::bail!("--forward-only cannot be used with --udp-sendto IP:auto, which only addresses return packets");
+        }
+        if (flow_diagnostics && listen) || flow_port_range.is_some() || prewarm_payload.is_some() {
+            eyre::bail!("--flow-diagnostics, --flow-port-range and --prewarm-payload need per-flow sockets, \
+                which --forward-only does not create");
+        }
+        if return_tcp_addr.is_some() {
+            eyre::bail!("--return-tcp-listen/--return-tcp-connect cannot be used with --forward-only");
+        }
+    }
+    if return_only && (udp_bind_spec.is_auto() || loopback) {
+        eyre::bail!("--return-only requires a fixed --udp-bind without --loopback: per-flow sockets are only created \
+            by forward packets");
+    }
+
+    let opts = RelayOptions {
+        listen,
+        loopback,
+        tcp_addr,
+        extra_tcp_listen,
+        return_tcp_addr,
+        udp_bind_spec,
+        udp_sendto_spec,
+        verbose,
+        debug,
+        tcp_listen_backlog,
+        flow_diagnostics,
+        sticky_flows,
+        forward_only,
+        return_only,
+        shard,
+        flow_port_range,
+        prewarm_payload,
+        sendto_allow,
+        routes,
+        match_prefixes,
+        netflow,
+        sendto_port_offset,
+        write_timeout,
+        handshake_timeout,
+        keepalive_interval,
+        run_as,
+        udp_batch_recv,
+        udp_rebind_after,
+        new_flow_threshold,
+        max_memory,
+        tx_watermarks,
+        reject_new_connections,
+        hello_flags,
+        client_token,
+        dump_wire_bytes,
+    };
+    if !loopback {
+        return run_relay(opts, None).await.map(drop);
+    }
+
+    // --loopback: both halves in this process, joined by an in-memory pipe instead of TCP. The
+    // connect half serves the given --udp-bind, the listen half forwards to the given --udp-sendto.
+    let listen_opts = RelayOptions {
+        listen: true,
+        udp_bind_spec: PortSpec::Auto(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        ..opts.clone()
+    };
+    let connect_opts = RelayOptions {
+        udp_sendto_spec: PortSpec::Auto(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        ..opts
+    };
+    let (connect_end, listen_end) = tokio::io::duplex(LOOPBACK_PIPE_CAPACITY);
+    tokio::try_join!(
+        run_relay(connect_opts, Some(Box::new(connect_end))),
+        run_relay(listen_opts, Some(Box::new(listen_end))),
+    )?;
+    Ok(())
+}
+
+/// Settings of one relay endpoint, as validated from the command line.
+#[derive(Clone)]
+struct RelayOptions {
+    listen: bool,
+    loopback: bool,
+    tcp_addr: SocketAddr,
+    extra_tcp_listen: Vec<SocketAddr>,
+    /// --return-tcp-listen or --return-tcp-connect, depending on the side
+    return_tcp_addr: Option<SocketAddr>,
+    udp_bind_spec: PortSpec,
+    udp_sendto_spec: PortSpec,
+    verbose: bool,
+    debug: bool,
+    tcp_listen_backlog: Option<u32>,
+    flow_diagnostics: bool,
+    sticky_flows: bool,
+    /// One-way tunnel: --forward-only carries only connect-to-listen traffic, --return-only the reverse
+    forward_only: bool,
+    return_only: bool,
+    shard: Option<(usize, usize)>,
+    flow_port_range: Option<(u16, u16)>,
+    prewarm_payload: Option<Vec<u8>>,
+    sendto_allow: Vec<IpNet>,
+    routes: Vec<(IpNet, SocketAddr)>,
+    match_prefixes: Vec<Vec<u8>>,
+    netflow: Option<SocketAddr>,
+    sendto_port_offset: i32,
+    write_timeout: Option<Duration>,
+    handshake_timeout: Duration,
+    keepalive_interval: Option<Duration>,
+    run_as: Option<RunAs>,
+    udp_batch_recv: usize,
+    udp_rebind_after: u32,
+    new_flow_threshold: u64,
+    max_memory: Option<usize>,
+    tx_watermarks: Option<(usize, usize)>,
+    reject_new_connections: bool,
+    hello_flags: u32,
+    client_token: Vec<u8>,
+    /// Bytes of each frame shown by --dump-wire; 0 disables dumping
+    dump_wire_bytes: usize,
+}
+
+/// Capacity of the in-memory pipe joining the two halves of --loopback.
+const LOOPBACK_PIPE_CAPACITY: usize = 1024 * 1024;
+
+/// Runs one relay endpoint until shutdown. `initial` is an already connected transport (the
+/// --loopback pipe); without it the endpoint listens for or connects to `tcp_addr`. Returns the
+/// lifetime totals, which have also been logged.
+async fn run_relay(opts: RelayOptions, initial: Option<Box<dyn Transport>>) -> eyre::Result<LifetimeStats> {
+    let RelayOptions {
+        listen,
+        loopback,
+        tcp_addr,
+        extra_tcp_listen,
+        return_tcp_addr,
+        udp_bind_spec,
+        udp_sendto_spec,
+        verbose,
+        debug,
+        tcp_listen_backlog,
+        flow_diagnostics,
+        sticky_flows,
+        forward_only,
+        return_only,
+        shard,
+        flow_port_range,
+        prewarm_payload,
+        sendto_allow,
+        routes,
+        match_prefixes,
+        netflow,
+        sendto_port_offset,
+        write_timeout,
+        handshake_timeout,
+        keepalive_interval,
+        run_as,
+        udp_batch_recv,
+        udp_rebind_after,
+        new_flow_threshold,
+        max_memory,
+        tx_watermarks,
+        reject_new_connections,
+        hello_flags,
+        client_token,
+        dump_wire_bytes,
+    } = opts;
+
+    // Side label carried as a field by the per-flow log lines
+    let side = if listen { "listen" } else { "connect" };
+    let mode = match (listen, loopback) {
+        (true, false) => "LISTEN",
+        (false, false) => "CONNECT",
+        (true, true) => "LOOPBACK LISTEN",
+        (false, true) => "LOOPBACK CONNECT",
+    };
     tracing::info!("Starting udp-over-tcp - Mode: {}, TCP: {:?}, UDP bind: {:?}, UDP sendto: {:?}",
-        if listen { "LISTEN" } else { "CONNECT" }, tcp_addr, udp_bind_spec, udp_sendto_spec);
+        mode, tcp_addr, udp_bind_spec, udp_sendto_spec);
+
+    // A one-way tunnel leaves out the direction it does not carry on each side: UDP received on the
+    // listen side is return traffic, data frames received on the connect side are too
+    let udp_receive = if listen { !forward_only } else { !return_only };
+    let tcp_deliver = if listen { !return_only } else { !forward_only };
+    // Per-flow sockets exist to receive returns, so forward-only sends from the shared socket
+    let per_flow_sockets = listen && udp_bind_spec.is_auto() && !forward_only;
+    if forward_only || return_only {
+        tracing::info!("One-way tunnel: only {} packets are relayed",
+            if forward_only { "forward (connect side to listen side)" } else { "return (listen side to connect side)" });
+    }
 
     // Create primary UDP socket: used for all traffic in fixed mode, or as placeholder in auto mode
-    let udp = match &udp_bind_spec {
+    let udp_bind_addr = match &udp_bind_spec {
         PortSpec::Fixed(addr) => {
             tracing::debug!("bind to udp {:?}", addr);
-            tokio::net::UdpSocket::bind(addr)
-                .await
-                .expect("udp-bind")
+            *addr
         }
         PortSpec::Auto(ip) => {
             // Auto bind mode: create placeholder socket, real per-flow sockets created dynamically
             let temp_addr = SocketAddr::new(*ip, 0);
             tracing::debug!("auto bind mode - creating placeholder socket on {:?}", temp_addr);
-            tokio::net::UdpSocket::bind(temp_addr)
-                .await
-                .expect("udp-bind")
+            temp_addr
         }
+        PortSpec::Pool(_) => unreachable!("destination pools are only parsed for --udp-sendto-roundrobin"),
+    };
+    let mut udp = tokio::net::UdpSocket::bind(udp_bind_addr)
+        .await
+        .expect("udp-bind");
+    // The listen side sends to backends from this socket unless every flow has its own
+    if listen {
+        report_unreachable(&udp);
+    }
+    // Consecutive hard receive errors on the main UDP socket (--udp-rebind-after)
+    let mut udp_recv_errors = 0u32;
+    // Set while `udp` is only a placeholder because a rebind lost the fixed port: next attempt and its delay
+    let mut udp_rebind_retry = None::<(Pin<Box<tokio::time::Sleep>>, Duration)>;
+    // Whether the last idle sweep left expired flows behind (FLOW_TEARDOWN_BATCH)
+    let mut idle_backlog = false;
+
+    // Per-flow sockets in auto mode bind to the same IP (and therefore family) as the placeholder
+    let flow_bind_addr = match &udp_bind_spec {
+        PortSpec::Auto(ip) => SocketAddr::new(*ip, 0),
+        _ => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
     };
 
     // Flow management data structures for auto mode
     let mut flow_sockets: HashMap<SocketAddr, tokio::net::UdpSocket> = HashMap::new();     // client_addr -> dedicated_socket
-    let mut socket_last_activity: HashMap<SocketAddr, SystemTime> = HashMap::new();       // client_addr -> last_activity_time
+    let mut socket_last_activity: HashMap<SocketAddr, Instant> = HashMap::new();          // client_addr -> last_activity_time
     let mut socket_to_client: HashMap<SocketAddr, SocketAddr> = HashMap::new();           // socket_port_key -> original_client_addr
+    let mut flow_destinations: HashMap<SocketAddr, SocketAddr> = HashMap::new();          // client_addr -> assigned_pool_destination
+    let mut flow_routes: HashMap<SocketAddr, Option<SocketAddr>> = HashMap::new();        // client_addr -> matching --route destination
+    let mut pool_next = 0usize;                                                            // next round-robin pool index
+    let mut pool_health = PoolHealth::new(&udp_sendto_spec);                               // passive health of pool destinations
+    let mut flow_port_next = 0u16;                                                         // next offset tried in --flow-port-range
+    let mut closing_sockets: Vec<tokio::net::UdpSocket> = Vec::new();                     // flow sockets awaiting close (FLOW_TEARDOWN_BATCH)
+    let mut flow_recv_errors: HashMap<SocketAddr, u32> = HashMap::new();                  // client_addr -> consecutive return recv errors
+    let mut flow_creation_resume_at = None::<std::time::Instant>;                          // end of the fd exhaustion cooldown
+    let mut last_shared_flow = None::<SocketAddr>;                                         // client of the latest send on the shared socket
+    let mut unmapped_returns = 0u64;                                                       // return packets whose reverse mapping was missing
+    let mut unmapped_return_logged_at = None::<std::time::Instant>;                        // rate limit for their warning
 
     // Flow activity tracking for timeout management (both sides use socket_last_activity)
-    let mut listener = if listen {
-        tracing::info!("bind to tcp {tcp_addr:?}");
-        Some(
-            tokio::net::TcpListener::bind(tcp_addr)
-                .await
-                .expect("tcp-listen"),
-        )
-    } else {
-        None
+    // Every --tcp-listen address gets a listener; connections from all of them feed the single active connection
+    let mut listeners = Vec::new();
+    if listen && !loopback {
+        for addr in std::iter::once(tcp_addr).chain(extra_tcp_listen) {
+            tracing::info!("bind to tcp {addr:?}");
+            listeners.push(bind_tcp_listener(addr, tcp_listen_backlog).await.expect("tcp-listen"));
+        }
+    }
+    let return_listener = match return_tcp_addr {
+        Some(addr) if listen => {
+            tracing::info!("bind to tcp {addr:?} for the return path");
+            Some(bind_tcp_listener(addr, tcp_listen_backlog).await.expect("return-tcp-listen"))
+        }
+        _ => None,
     };
-    let mut tcp = None::<tokio::net::TcpStream>;
+    // Everything that may need a privileged port is bound now
+    if let Some(run_as) = run_as {
+        drop_privileges(run_as).wrap_err("drop privileges")?;
+    }
+    let mut tcp = None::<Box<dyn Transport>>;
+    // Dedicated return path (--return-tcp-listen/--return-tcp-connect): while connected, the listen
+    // side writes return packets here instead of to `tcp`, in the framing negotiated on `tcp`
+    let mut return_tcp = None::<tokio::net::TcpStream>;
+    let mut return_tcp_buf = Vec::new();
+    let mut return_connect_again = None::<Pin<Box<tokio::time::Sleep>>>;
+    // Peer of the accepted connection (listen side), for logging when another one arrives
+    let mut tcp_peer = None::<SocketAddr>;
+    // Accepted connections (listen side) whose Hello exchange is still running
+    let mut handshakes = tokio::task::JoinSet::new();
+    // Send queue of the TCP connection for --tx-high-water; UDP receive is paused while above it
+    let mut tcp_send_queue = None::<SendQueueProbe>;
+    let mut udp_backpressure = false;
+    // Since when the send queue has been near capacity, and whether that was already reported
+    let mut send_queue_full_since = None::<(std::time::Instant, bool)>;
+    // Per-connection wire settings, negotiated via Hello control frames
+    let mut hello_sent = false;
+    let mut addr_format = AddrFormat::Full;
+    let mut frame_format = FrameFormat::Legacy;
+    // Capabilities the peer announced in its Hello (0 without an exchange)
+    let mut peer_hello_flags = 0u32;
+    // Whether the peer sent a Goodbye on the current connection: its close is then a deliberate shutdown
+    let mut peer_goodbye = false;
     let mut connect_again = None::<Pin<Box<tokio::time::Sleep>>>;
-
+    // Client token of the current connection (listen side), and flows parked after it dropped
+    let mut session_token = Vec::new();
+    let mut parked_session = None::<(Vec<u8>, Pin<Box<tokio::time::Sleep>>)>;
+    // --keepalive-interval: sequence number and send time of the latest keepalive awaiting its echo
+    let mut keepalive_timer = keepalive_interval.map(|period| {
+        let mut timer = tokio::time::interval(period);
+        timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        timer
+    });
+    let mut keepalive_seq = 0u32;
+    let mut keepalive_sent = None::<(u32, std::time::Instant)>;
+    let mut rtt_logged_at = std::time::Instant::now();
+
+    // UDP receive buffers stay at full length for their whole lifetime: recv calls write into
+    // the slice and report the datagram length, so no per-packet resize/zero-fill is needed.
     let mut udp_buf = vec![0; 1024 * 1024]; // Large buffer for UDP packets
     let mut tcp_buf = Vec::with_capacity(65536);
+    // Start of the unprocessed bytes in tcp_buf; everything before it was already handled
+    let mut tcp_buf_start = 0;
     let mut return_buf = vec![0; 1024 * 1024]; // Separate buffer for return packets
+    // Extra datagram buffers for --udp-batch-recv, filled after the first datagram of a batch
+    let mut batch_bufs = vec![vec![0; 65535]; udp_batch_recv - 1];
+    let fixed_buffer_bytes = udp_buf.len() + return_buf.len() + batch_bufs.len() * 65535;
+    if let Some(limit) = max_memory {
+        if limit < fixed_buffer_bytes + MAX_HANDSHAKE_FRAME_LEN {
+            eyre::bail!("--max-memory must be at least {} bytes with these options (fixed buffers plus one maximum-size frame)",
+                fixed_buffer_bytes + MAX_HANDSHAKE_FRAME_LEN);
+        }
+    }
+
+    // A transport handed in already connected (--loopback) is set up like a fresh connection
+    if let Some(mut transport) = initial {
+        if hello_flags != 0 {
+            let (peer_flags, _) = perform_handshake(&mut transport, hello_flags, &client_token, handshake_timeout, &mut tcp_buf, dump_wire_bytes)
+                .await
+                .wrap_err("loopback handshake")?;
+            hello_sent = true;
+            (addr_format, frame_format) = negotiate_wire_format(hello_flags, peer_flags);
+            peer_hello_flags = peer_flags;
+        }
+        tcp = Some(transport);
+    }
 
-    // Debug tracking for flow statistics
+    // Debug tracking for flow statistics, also reported when a flow is cleaned up
     let mut flow_packet_counts: HashMap<SocketAddr, u64> = HashMap::new();
+    let mut flow_byte_counts: HashMap<SocketAddr, u64> = HashMap::new();
+    // Flow record export (--netflow), fed from the flow tables above
+    let mut netflow = match netflow {
+        Some(collector) => Some(netflow::Exporter::new(collector).wrap_err("--netflow socket")?),
+        None => None,
+    };
+    let mut netflow_swept_at = std::time::Instant::now();
+
+    // Lifetime aggregates for the shutdown summary
+    let started = std::time::Instant::now();
+    let mut stats = LifetimeStats::default();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    // Maintenance pause: forwarding stops in both directions, connection and flows are kept
+    let mut pause_toggle = PauseToggle::new();
+    let mut paused_since = None::<std::time::Instant>;
+    let mut paused_drops_at_pause = 0u64;
+
+    /// Macro to queue the NetFlow record of a flow that is about to be removed (--netflow).
+    macro_rules! export_ended_flow {
+        ($flow_addr:expr) => {
+            if let Some(exporter) = &mut netflow {
+                let flow_addr = $flow_addr;
+                exporter.flow_ended(
+                    flow_addr,
+                    flow_packet_counts.get(&flow_addr).copied().unwrap_or(0),
+                    flow_byte_counts.get(&flow_addr).copied().unwrap_or(0),
+                    wall_clock(socket_last_activity.get(&flow_addr).copied().unwrap_or_else(Instant::now)),
+                );
+            }
+        };
+    }
 
     /// Macro to clean up all flow state when TCP connection drops.
     /// This prevents stale flow mappings from causing routing issues after reconnection.
@@ -245,10 +1672,18 @@ async fn main() -> eyre::Result<()> {
             let mapping_count = socket_to_client.len();
             let activity_count = socket_last_activity.len();
 
-            flow_sockets.clear();
+            for &flow_addr in socket_last_activity.keys() {
+                export_ended_flow!(flow_addr);
+            }
+            // The sockets themselves are closed a batch at a time after the select
+            closing_sockets.extend(flow_sockets.drain().map(|(_, socket)| socket));
             socket_to_client.clear();
             socket_last_activity.clear();
             flow_packet_counts.clear();
+            flow_byte_counts.clear();
+            flow_destinations.clear();
+            flow_routes.clear();
+            flow_recv_errors.clear();
 
             if flow_count > 0 || mapping_count > 0 || activity_count > 0 {
                 tracing::info!("Cleaned up flow state: {} sockets, {} mappings, {} activity entries",
@@ -257,13 +1692,181 @@ async fn main() -> eyre::Result<()> {
         };
     }
 
+    /// Macro to remove a single flow from every flow table, closing its socket if it owns one.
+    /// Evaluates to true when a flow socket was closed.
+    macro_rules! remove_flow {
+        ($flow_addr:expr) => {{
+            let flow_addr = $flow_addr;
+            export_ended_flow!(flow_addr);
+            socket_last_activity.remove(&flow_addr);
+            flow_packet_counts.remove(&flow_addr);
+            flow_byte_counts.remove(&flow_addr);
+            flow_destinations.remove(&flow_addr);
+            flow_routes.remove(&flow_addr);
+            flow_recv_errors.remove(&flow_addr);
+            if let Some(socket) = flow_sockets.remove(&flow_addr) {
+                // Also clean up reverse mapping (keyed by port only, whatever the bind IP)
+                if let Ok(socket_local_addr) = socket.local_addr() {
+                    let port_key = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), socket_local_addr.port());
+                    socket_to_client.remove(&port_key);
+                }
+                true
+            } else {
+                false
+            }
+        }};
+    }
+
+    /// Macro checking whether `$extra` more bytes would push the accounted memory (fixed buffers,
+    /// the TCP receive buffer and an estimate per flow) over --max-memory.
+    macro_rules! memory_exceeded {
+        ($extra:expr) => {
+            max_memory.is_some_and(|limit| {
+                fixed_buffer_bytes + tcp_buf.capacity() + socket_last_activity.len() * FLOW_MEMORY_ESTIMATE + $extra > limit
+            })
+        };
+    }
+
+    /// Macro to release flow state when the TCP connection is lost. On the listen side, flows of a
+    /// connection that identified itself with a --client-token are parked for CLIENT_TOKEN_GRACE
+    /// instead, so the same client can pick them up again after reconnecting.
+    macro_rules! release_flow_state {
+        () => {
+            if listen && !session_token.is_empty() {
+                tracing::info!("Parking {} flows for {:?} awaiting reconnection of the same client",
+                    flow_sockets.len(), CLIENT_TOKEN_GRACE);
+                parked_session = Some((std::mem::take(&mut session_token), Box::pin(tokio::time::sleep(CLIENT_TOKEN_GRACE))));
+            } else {
+                cleanup_flow_state!();
+            }
+        };
+    }
+
+    /// Macro to settle parked flows once the new connection's client token (empty if it has none)
+    /// is known: the same token resumes them, anything else discards them.
+    macro_rules! resolve_parked_session {
+        ($token:expr) => {
+            let token: Vec<u8> = $token;
+            if let Some((parked_token, _)) = parked_session.take() {
+                if parked_token == token {
+                    tracing::info!("Client token matched, resuming {} parked flows", flow_sockets.len());
+                } else {
+                    cleanup_flow_state!();
+                }
+            }
+            session_token = token;
+        };
+    }
+
+    /// Macro to schedule the next connection attempt of the connect side after losing the
+    /// connection: soon after a failure, later when the listen side said it was shutting down.
+    macro_rules! schedule_reconnect {
+        () => {
+            let delay = if std::mem::take(&mut peer_goodbye) { PEER_SHUTDOWN_RECONNECT_DELAY } else { Duration::from_secs(3) };
+            tracing::info!("Will retry TCP connection in {} seconds...", delay.as_secs());
+            connect_again = Some(Box::pin(tokio::time::sleep(delay)));
+        };
+    }
+
+    /// Macro to bind the --udp-bind address for `udp`, which is a placeholder after a rebind
+    /// released the port. On failure the next attempt is scheduled after `$retry_delay`.
+    macro_rules! bind_udp_again {
+        ($retry_delay:expr) => {
+            match tokio::net::UdpSocket::bind(udp_bind_addr).await {
+                Ok(new_udp) => {
+                    udp = new_udp;
+                    if listen {
+                        report_unreachable(&udp);
+                    }
+                    udp_rebind_retry = None;
+                    tracing::info!("UDP socket rebound on {:?}", udp.local_addr().ok());
+                }
+                Err(e) => {
+                    let retry_delay = $retry_delay;
+                    tracing::error!("cannot bind UDP socket {:?} again: {}; retrying in {:?}", udp_bind_addr, e, retry_delay);
+                    udp_rebind_retry = Some((Box::pin(tokio::time::sleep(retry_delay)), retry_delay));
+                }
+            }
+        };
+    }
+
+    /// Macro to account for ICMP port unreachable reported on `$socket`: every destination drained
+    /// from its error queue counts as a failure, or `$fallback` (if any) where the platform does
+    /// not say which. Evaluates to the destinations, for logging.
+    macro_rules! record_unreachable {
+        ($socket:expr, $fallback:expr) => {{
+            let fallback: Option<SocketAddr> = $fallback;
+            let mut dests = take_unreachable($socket);
+            if dests.is_empty() {
+                dests.extend(fallback);
+            }
+            stats.backend_unreachable += dests.len() as u64;
+            for dest in &dests {
+                pool_health.record_failure(dest);
+            }
+            dests
+        }};
+    }
+
+    /// Macro to drop the TCP connection after a failed or timed-out write.
+    /// The connect side schedules a reconnection attempt, like after a read error.
+    macro_rules! drop_tcp_connection {
+        () => {
+            tcp = None;
+            release_flow_state!();
+            if !listen {
+                schedule_reconnect!();
+            }
+            peer_goodbye = false;
+        };
+    }
+
     loop {
         let has_tcp = tcp.is_some();
+        let send_queue = tcp_send_queue.filter(|_| has_tcp).and_then(|probe| Some((probe.queued()?, probe.capacity)));
+        if let Some((queued, sndbuf)) = send_queue {
+            stats.peak_send_queue = stats.peak_send_queue.max(queued);
+            if let Some((high, low)) = tx_watermarks {
+                if udp_backpressure && queued <= low {
+                    udp_backpressure = false;
+                    tracing::debug!("TCP send queue drained to {} bytes, resuming UDP receive", queued);
+                } else if !udp_backpressure && queued >= high {
+                    udp_backpressure = true;
+                    stats.backpressure_pauses += 1;
+                    tracing::debug!("TCP send queue at {} bytes, pausing UDP receive", queued);
+                }
+            }
+            // Sustained near-capacity queues mean the peer or the path cannot keep up
+            let capacity = tx_watermarks.map(|(high, _)| high).or(sndbuf);
+            if capacity.is_some_and(|capacity| queued * 100 >= capacity * SEND_QUEUE_NEAR_FULL_PERCENT) {
+                let (since, reported) = send_queue_full_since.get_or_insert((std::time::Instant::now(), false));
+                if !*reported && since.elapsed() >= SEND_QUEUE_WARN_AFTER {
+                    *reported = true;
+                    tracing::warn!("TCP send queue near capacity for {:?}: {} of {} bytes queued",
+                        SEND_QUEUE_WARN_AFTER, queued, capacity.unwrap_or_default());
+                }
+            } else {
+                send_queue_full_since = None;
+            }
+        } else {
+            udp_backpressure = false;
+            send_queue_full_since = None;
+        }
+        if !has_tcp && return_tcp.take().is_some() {
+            // The return path belongs to the session of the main connection and its framing
+            tracing::info!("closing return tcp connection along with the main one");
+            return_tcp_buf.clear();
+        }
+        if loopback && !has_tcp {
+            // The other half is gone and there is nothing to reconnect to
+            tracing::info!("loopback pipe closed, stopping");
+            break;
+        }
         if debug {
             tracing::debug!("Main loop iteration - has_tcp: {}, listen: {}", has_tcp, listen);
         }
         let connect_fut = async {
-            if !has_tcp && !listen {
+            if !has_tcp && !listen && !loopback {
                 if let Some(timeout) = &mut connect_again {
                     timeout.await;
                     connect_again = None;
@@ -275,13 +1878,43 @@ async fn main() -> eyre::Result<()> {
                 std::future::pending().await
             }
         };
-        let listener_fut = async {
-            if let Some(listener) = &mut listener {
+        let listeners_ref = &listeners;
+        let listener_fut = std::future::poll_fn(|cx| {
+            for listener in listeners_ref {
+                if let std::task::Poll::Ready(accepted) = listener.poll_accept(cx) {
+                    return std::task::Poll::Ready(accepted);
+                }
+            }
+            std::task::Poll::Pending
+        });
+        let has_return_tcp = return_tcp.is_some();
+        let return_connect_fut = async {
+            match return_tcp_addr {
+                Some(addr) if has_tcp && !has_return_tcp && !listen => {
+                    if let Some(timeout) = &mut return_connect_again {
+                        timeout.await;
+                        return_connect_again = None;
+                    }
+                    tracing::debug!("connect to tcp {addr:?} for the return path");
+                    tokio::net::TcpStream::connect(addr).await
+                }
+                _ => std::future::pending().await,
+            }
+        };
+        let return_accept_fut = async {
+            if let Some(listener) = &return_listener {
                 listener.accept().await
             } else {
                 std::future::pending().await
             }
         };
+        let return_read_fut = async {
+            if let Some(stream) = &mut return_tcp {
+                stream.read_buf(&mut return_tcp_buf).await
+            } else {
+                std::future::pending().await
+            }
+        };
         let tcp_fut = async {
             if let Some(tcp) = &mut tcp {
                 tcp.read_buf(&mut tcp_buf).await
@@ -289,93 +1922,272 @@ async fn main() -> eyre::Result<()> {
                 std::future::pending().await
             }
         };
+        let keepalive_fut = async {
+            if let Some(timer) = &mut keepalive_timer {
+                timer.tick().await;
+            } else {
+                std::future::pending().await
+            }
+        };
+        let has_parked_session = parked_session.is_some();
+        let udp_rebind_pending = udp_rebind_retry.is_some();
+        let udp_rebind_fut = async {
+            if let Some((retry, _)) = &mut udp_rebind_retry {
+                retry.await;
+            } else {
+                std::future::pending().await
+            }
+        };
+        let teardown_pending = !closing_sockets.is_empty() || idle_backlog;
+        let parked_fut = async {
+            if let Some((_, grace)) = &mut parked_session {
+                grace.await;
+            } else {
+                std::future::pending().await
+            }
+        };
+        // Wakes the loop when any flow socket has a return packet or an ICMP error queued, so the
+        // non-blocking return poll below runs without waiting for unrelated traffic. Queued errors
+        // only raise error readiness, which poll_recv_ready does not wake on.
+        let flow_sockets_ref = &flow_sockets;
+        let mut flow_errors: Vec<_> = if listen && cfg!(target_os = "linux") {
+            flow_sockets.values().map(|socket| Box::pin(socket.ready(tokio::io::Interest::ERROR))).collect()
+        } else {
+            Vec::new()
+        };
+        let return_ready_fut = std::future::poll_fn(move |cx| {
+            if flow_sockets_ref.values().any(|socket| socket.poll_recv_ready(cx).is_ready())
+                || flow_errors.iter_mut().any(|ready| std::future::Future::poll(ready.as_mut(), cx).is_ready())
+            {
+                std::task::Poll::Ready(())
+            } else {
+                std::task::Poll::Pending
+            }
+        });
 
         select! {
             // Handle incoming UDP packets (highest priority for low latency)
-            msg = udp.recv_from(&mut udp_buf) => {
+            // Not polled under backpressure, so bursts queue up in the kernel's UDP receive buffer
+            msg = udp.recv_from(&mut udp_buf), if udp_receive && !udp_backpressure => {
                 if debug {
                     tracing::debug!("UDP packet received on {} side", if listen { "listen" } else { "connect" });
                 }
-                if let Some(tcp_stream) = &mut tcp {
+                if paused_since.is_some() && msg.is_ok() {
+                    stats.paused_drops += 1;
+                } else if let Some(tcp_stream) = &mut tcp {
                     match msg {
                         Ok((len, from_addr)) => {
-                            if debug {
-                                tracing::debug!("UDP packet details: {} bytes from {}", len, from_addr);
+                            udp_recv_errors = 0;
+                            if listen {
+                                pool_health.record_success(&from_addr);
                             }
-                            // Wrap UDP packet with source address for TCP transmission
-                            let packet = UdpPacketWithSource {
-                                source: from_addr,
-                                data: udp_buf[..len].to_vec(),
+                            // Pull whatever else is already queued in the same call (--udp-batch-recv)
+                            let batch = if batch_bufs.is_empty() {
+                                Vec::new()
+                            } else {
+                                recv_udp_batch(&udp, &mut batch_bufs)
                             };
+                            let datagrams = std::iter::once((&udp_buf[..len], from_addr))
+                                .chain(batch.iter().enumerate().map(|(i, &(len, from))| (&batch_bufs[i][..len], from)));
+
+                            // All frames of the batch go out in a single write
+                            let mut frames = Vec::new();
+                            let mut frame_lens = Vec::new();
+                            for (data, from_addr) in datagrams {
+                                let len = data.len();
+                                // Content filter on the forward path: only payloads starting with a --match-prefix cross
+                                if !match_prefixes.is_empty() && !match_prefixes.iter().any(|prefix| data.starts_with(prefix)) {
+                                    stats.prefix_drops += 1;
+                                    if debug {
+                                        tracing::debug!("dropping UDP packet from {} - no --match-prefix matches", from_addr);
+                                    }
+                                    continue;
+                                }
+                                if !socket_last_activity.contains_key(&from_addr) && memory_exceeded!(FLOW_MEMORY_ESTIMATE) {
+                                    stats.memory_drops += 1;
+                                    tracing::warn!("dropping UDP packet from {} - new flow would exceed --max-memory", from_addr);
+                                    continue;
+                                }
+                                if debug {
+                                    tracing::debug!("UDP packet details: {} bytes from {}", len, from_addr);
+                                }
+                                // Wrap UDP packet with source address for TCP transmission
+                                let packet = UdpPacketWithSource {
+                                    source: from_addr,
+                                    data: data.to_vec(),
+                                };
 
-                            // Connect side with auto sendto: return packets contain original client address directly
-                            // No additional mapping needed as packet source metadata handles routing
+                                // Connect side with auto sendto: return packets contain original client address directly
+                                // No additional mapping needed as packet source metadata handles routing
 
-                            // Track flow and log new flows
-                            let count = flow_packet_counts.entry(from_addr).or_insert(0);
+                                // Track flow and log new flows
+                                *flow_byte_counts.entry(from_addr).or_insert(0) += len as u64;
+                                let count = flow_packet_counts.entry(from_addr).or_insert(0);
 
-                            // Update activity timestamp for this flow
-                            socket_last_activity.insert(from_addr, SystemTime::now());
+                                // Update activity timestamp for this flow
+                                socket_last_activity.insert(from_addr, Instant::now());
 
-                            if *count == 0 && verbose {
-                                // Determine destination for logging
-                                let dest_desc = match &udp_sendto_spec {
-                                    PortSpec::Fixed(addr) => format!("{}", addr),
-                                    PortSpec::Auto(_) => "auto".to_string(),
-                                };
-                                tracing::info!("[FLOW] New UDP flow established: {} -> {} via {} ({})",
-                                    from_addr, dest_desc,
-                                    udp.local_addr().unwrap_or_else(|_|
-                                        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)),
-                                    if listen { "listen side" } else { "connect side" });
-                            }
-                            *count += 1;
+                                if *count == 0 {
+                                    stats.total_flows += 1;
+                                    if let Some(exporter) = &mut netflow {
+                                        // The flow ends at this relay's UDP address, where it enters the tunnel. That is the
+                                        // --udp-bind address, so a wildcard bind is exported as 0.0.0.0 with the bound port:
+                                        // the unconnected socket does not learn which local IP each client sent to.
+                                        if let Ok(local_addr) = udp.local_addr() {
+                                            exporter.flow_started(from_addr, local_addr);
+                                        }
+                                    }
+                                }
+                                // Logged once the flow reaches --new-flow-threshold packets
+                                if *count + 1 == new_flow_threshold && verbose {
+                                    // Determine destination for logging
+                                    let dest_desc = match &udp_sendto_spec {
+                                        PortSpec::Fixed(addr) => format!("{}", addr),
+                                        PortSpec::Auto(_) => "auto".to_string(),
+                                        PortSpec::Pool(_) => "pool".to_string(),
+                                    };
+                                    let local_addr = udp.local_addr().unwrap_or_else(|_|
+                                        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+                                    tracing::info!(%side, src = %from_addr, dst = %dest_desc, local = %local_addr, "flow established");
+                                }
+                                *count += 1;
 
-                            if debug {
-                                // Use cached local address to avoid expensive socket creation
-                                let local_addr = udp.local_addr().unwrap_or_else(|_|
-                                    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+                                if debug {
+                                    // Use cached local address to avoid expensive socket creation
+                                    let local_addr = udp.local_addr().unwrap_or_else(|_|
+                                        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+
+                                    tracing::info!(%side, src = %from_addr, dst = "tunnel", local = %local_addr, bytes = len, count = *count,
+                                        "datagram received");
+                                }
 
-                                tracing::info!("[DEBUG] UDP datagram #{}: {} -> target ({} bytes) via {} ({})",
-                                    count, from_addr, len, local_addr,
-                                    if listen { "listen side" } else { "connect side" });
+                                // Send through TCP tunnel with enhanced protocol
+                                let frame_start = frames.len();
+                                match packet.serialize(addr_format)
+                                    .and_then(|serialized| encode_frame(frame_format, FRAME_TYPE_DATA, &serialized, &mut frames))
+                                {
+                                    Ok(()) => {
+                                        dump_frame(dump_wire_bytes, "tx", &[], &frames[frame_start..]);
+                                        frame_lens.push(len);
+                                    }
+                                    Err(e) => tracing::warn!("dropping UDP packet from {}: {e}", from_addr),
+                                }
                             }
 
-                            // Send through TCP tunnel with enhanced protocol
-                            let serialized = packet.serialize();
-                            let len_bytes = (serialized.len() as u32).to_le_bytes();
-
-                            if let Err(e) = tcp_stream.write_all(&len_bytes).await {
-                                tracing::error!("dropping tcp connection after failed write: {e}");
-                                tcp = None;
-                                cleanup_flow_state!();
-                            } else if let Err(e) = tcp_stream.write_all(&serialized).await {
-                                tracing::error!("dropping tcp connection after failed write: {e}");
-                                tcp = None;
-                                cleanup_flow_state!();
-                            } else if let Err(e) = tcp_stream.flush().await {
-                                tracing::error!("dropping tcp connection after failed flush: {e}");
-                                tcp = None;
-                                cleanup_flow_state!();
+                            if !frames.is_empty() {
+                                // Returns take the dedicated return path while there is one
+                                let written = match &mut return_tcp {
+                                    Some(return_stream) if listen => {
+                                        with_write_timeout(write_timeout, write_encoded_frames(return_stream, &frames)).await
+                                    }
+                                    _ => with_write_timeout(write_timeout, write_encoded_frames(tcp_stream, &frames)).await,
+                                };
+                                if let Err(e) = written {
+                                    if listen && return_tcp.take().is_some() {
+                                        tracing::error!("dropping return tcp connection after failed write: {e}");
+                                    } else {
+                                        tracing::error!("dropping tcp connection after failed write: {e}");
+                                        drop_tcp_connection!();
+                                    }
+                                } else {
+                                    for len in frame_lens {
+                                        if listen {
+                                            stats.record_return(len);
+                                        } else {
+                                            stats.record_forward(len);
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) if is_port_unreachable(&e) => {
+                            // Only the listen side's forward path sends to backends from the shared socket.
+                            // Without the error queue there is no address: charge the backend of the flow sent last
+                            let fallback = last_shared_flow.and_then(|client| flow_destinations.get(&client).copied());
+                            let dests = record_unreachable!(&udp, fallback);
+                            match (dests.as_slice(), &udp_sendto_spec) {
+                                ([_, ..], _) => {
+                                    for dest in dests {
+                                        tracing::warn!("backend not listening on {} ({})", dest, e);
+                                    }
+                                }
+                                ([], PortSpec::Fixed(addr)) => tracing::warn!("backend not listening on {} ({})", addr, e),
+                                ([], _) => tracing::warn!("UDP peer not listening ({})", e),
                             }
                         }
+                        Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted) => {
+                            // Transient: nothing to read after all
+                        }
                         Err(e) => {
                             tracing::error!("UDP recv failed: {}", e);
-                            // Brief delay to prevent tight error loops when UDP socket is in bad state
-                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            udp_recv_errors += 1;
+                            if udp_recv_errors >= udp_rebind_after {
+                                // The socket may be stuck in a bad state: replace it with a fresh one on the same
+                                // address. A failed rebind never stops the relay.
+                                tracing::warn!("UDP socket failed {} receives in a row, rebinding {:?}", udp_recv_errors, udp_bind_addr);
+                                udp_recv_errors = 0;
+                                match tokio::net::UdpSocket::bind(udp_bind_addr).await {
+                                    Ok(new_udp) => {
+                                        // Bound next to the old socket (port 0), which the swap closes
+                                        udp = new_udp;
+                                        if listen {
+                                            report_unreachable(&udp);
+                                        }
+                                        tracing::info!("UDP socket rebound on {:?}", udp.local_addr().ok());
+                                    }
+                                    Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && udp_bind_addr.port() != 0 => {
+                                        // The old socket holds the fixed port: release it, with a placeholder standing
+                                        // in until the port is bound again
+                                        match tokio::net::UdpSocket::bind(SocketAddr::new(udp_bind_addr.ip(), 0)).await {
+                                            Ok(placeholder) => {
+                                                drop(std::mem::replace(&mut udp, placeholder));
+                                                bind_udp_again!(UDP_REBIND_RETRY_MIN);
+                                            }
+                                            Err(e) => tracing::warn!("cannot rebind UDP socket {:?}: {}; keeping the current one",
+                                                udp_bind_addr, e),
+                                        }
+                                    }
+                                    // E.g. a privileged port after --user dropped root
+                                    Err(e) => tracing::warn!("cannot rebind UDP socket {:?}: {}; keeping the current one",
+                                        udp_bind_addr, e),
+                                }
+                            } else {
+                                // Brief delay to prevent tight error loops when UDP socket is in bad state
+                                tokio::time::sleep(Duration::from_millis(100)).await;
+                            }
                         }
                     }
-                    udp_buf.resize(udp_buf.capacity(), 0); // Restore buffer to full capacity for next read
                 } else {
                     tracing::info!("DROPPING UDP packet - no TCP connection established yet");
                 }
             }
-            conn = connect_fut, if !has_tcp && !listen => {
+            conn = connect_fut, if !has_tcp && !listen && !loopback => {
                 match conn {
-                    Ok(stream) => {
-                        tracing::info!("✅ TCP connection established on CONNECT side to {:?}", tcp_addr);
-                        tcp = Some(stream);
+                    Ok(mut stream) => {
                         tcp_buf.clear();
+                        tcp_buf_start = 0;
+                        hello_sent = false;
+                        addr_format = AddrFormat::Full;
+                        frame_format = FrameFormat::Legacy;
+                        peer_hello_flags = 0;
+                        if hello_flags != 0 {
+                            match perform_handshake(&mut stream, hello_flags, &client_token, handshake_timeout, &mut tcp_buf, dump_wire_bytes).await {
+                                Ok((peer_flags, _)) => {
+                                    hello_sent = true;
+                                    (addr_format, frame_format) = negotiate_wire_format(hello_flags, peer_flags);
+                                    peer_hello_flags = peer_flags;
+                                }
+                                Err(e) => {
+                                    tracing::error!("tcp handshake failed: {e:#}");
+                                    connect_again = Some(Box::pin(tokio::time::sleep(Duration::from_secs(1))));
+                                    continue;
+                                }
+                            }
+                        }
+                        tracing::info!(%side, peer = %tcp_addr, "tcp connection established");
+                        stats.connections += 1;
+                        tcp_send_queue = Some(SendQueueProbe::new(&stream));
+                        tcp = Some(Box::new(stream));
                     }
                     Err(e) => {
                         tracing::error!("tcp connect failed: {e}");
@@ -383,36 +2195,236 @@ async fn main() -> eyre::Result<()> {
                     }
                 }
             }
+            conn = return_connect_fut, if has_tcp && !has_return_tcp && !listen && return_tcp_addr.is_some() => {
+                match conn {
+                    Ok(stream) => {
+                        tracing::info!(%side, peer = ?stream.peer_addr().ok(), "return tcp connection established");
+                        return_tcp_buf.clear();
+                        return_tcp = Some(stream);
+                    }
+                    Err(e) => {
+                        tracing::error!("return tcp connect failed: {e}");
+                        return_connect_again = Some(Box::pin(tokio::time::sleep(Duration::from_secs(1))));
+                    }
+                }
+            }
+            conn = return_accept_fut, if return_listener.is_some() => {
+                let (conn, addr) = match conn {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::error!("return tcp accept failed: {e}");
+                        continue;
+                    }
+                };
+                // Returns use the framing of the main connection, so there must be one
+                let Some(main_peer) = tcp.as_ref().and(tcp_peer) else {
+                    tracing::warn!("rejecting return tcp connection from {addr:?}: no main connection");
+                    continue;
+                };
+                // The return path belongs to the main session: it has to come from the same host, and an
+                // established one is never replaced (as with --reject-new-connections for the main connection),
+                // so no other client can take over the return traffic
+                if addr.ip() != main_peer.ip() {
+                    tracing::warn!("rejecting return tcp connection from {addr:?}: main connection is from {main_peer:?}");
+                    continue;
+                }
+                if return_tcp.is_some() {
+                    tracing::warn!("rejecting return tcp connection from {addr:?}: a return connection is already active");
+                    continue;
+                }
+                tracing::info!("accepted return tcp connection from {addr:?}");
+                return_tcp = Some(conn);
+            }
+            msg = return_read_fut => {
+                match msg {
+                    Ok(0) | Err(_) => {
+                        match msg {
+                            Err(e) => tracing::error!("return tcp connection error: {e}"),
+                            _ => tracing::warn!("return tcp connection closed by remote"),
+                        }
+                        // Returns go over the main connection until the return path is back
+                        return_tcp = None;
+                        return_tcp_buf.clear();
+                        if !listen {
+                            return_connect_again = Some(Box::pin(tokio::time::sleep(Duration::from_secs(3))));
+                        }
+                        continue;
+                    }
+                    Ok(_) => {}
+                }
+                // Only the listen side writes on the return path
+                if listen {
+                    return_tcp_buf.clear();
+                    continue;
+                }
+
+                let mut rest = &return_tcp_buf[..];
+                while let Ok((header, msg, remaining)) = split_frame(frame_format, rest) {
+                    dump_frame(dump_wire_bytes, "rx", &rest[..frame_format.header_len()], msg);
+                    rest = remaining;
+                    if header.frame_type != FRAME_TYPE_DATA {
+                        continue;
+                    }
+                    if paused_since.is_some() {
+                        stats.paused_drops += 1;
+                        continue;
+                    }
+                    let packet = match UdpPacketWithSource::deserialize(msg, addr_format) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            tracing::error!("Failed to parse UDP packet from return TCP stream: {e}");
+                            continue;
+                        }
+                    };
+
+                    // Same destination rules as for returns arriving on the main connection
+                    let dest_addr = match &udp_sendto_spec {
+                        PortSpec::Fixed(addr) => *addr,
+                        _ => packet.source,
+                    };
+                    let dest_addr = if udp_sendto_spec.is_auto() {
+                        match derived_destination(dest_addr, sendto_port_offset, &sendto_allow, &mut stats) {
+                            Some(dest) => dest,
+                            None => continue,
+                        }
+                    } else {
+                        dest_addr
+                    };
+
+                    *flow_byte_counts.entry(packet.source).or_insert(0) += packet.data.len() as u64;
+                    *flow_packet_counts.entry(packet.source).or_insert(0) += 1;
+                    socket_last_activity.insert(packet.source, Instant::now());
+                    if debug {
+                        tracing::info!(%side, src = %packet.source, dst = %dest_addr, bytes = packet.data.len(),
+                            "datagram received on return path");
+                    }
+                    if let Err(e) = udp.send_to(&packet.data, dest_addr).await {
+                        tracing::error!("udp forward failed: {e}");
+                    } else {
+                        stats.record_return(packet.data.len());
+                    }
+                }
+
+                // Nothing larger than a datagram with its header is ever sent on the return path
+                if let Ok(header) = FrameHeader::decode(frame_format, rest) {
+                    if header.len > MAX_HANDSHAKE_FRAME_LEN {
+                        tracing::error!("dropping return tcp connection: incoming frame of {} bytes", header.len);
+                        return_tcp = None;
+                        return_tcp_buf.clear();
+                        return_connect_again = Some(Box::pin(tokio::time::sleep(Duration::from_secs(3))));
+                        continue;
+                    }
+                }
+                let keep = return_tcp_buf.len() - rest.len();
+                return_tcp_buf.drain(..keep);
+            }
             conn = listener_fut, if listen => {
-                let (conn, addr) = conn.expect("TcpListener::accept only fails if out of FDs or on protocol errors");
-                if let Some(old) = tcp.replace(conn) {
-                    tracing::warn!(
-                        "new tcp connection from {addr:?} replaces old {:?}",
-                        old.peer_addr().expect("TcpStream::peer_addr never fails")
-                    );
+                let (mut conn, addr) = conn.expect("TcpListener::accept only fails if out of FDs or on protocol errors");
+                if reject_new_connections {
+                    if let (Some(_), Some(active)) = (&tcp, tcp_peer) {
+                        // The established session is authoritative; closing `conn` rejects the newcomer
+                        tracing::warn!(
+                            "rejecting new tcp connection from {addr:?}, keeping active connection from {active:?}"
+                        );
+                        continue;
+                    }
+                }
+                // The Hello exchange runs as its own task, so forwarding on the current connection
+                // continues while a slow or stalled peer is still negotiating
+                handshakes.spawn(async move {
+                    let mut pending = Vec::new();
+                    let result = if hello_flags != 0 {
+                        Some(perform_handshake(&mut conn, hello_flags, &[], handshake_timeout, &mut pending, dump_wire_bytes).await)
+                    } else {
+                        None
+                    };
+                    (conn, addr, result, pending)
+                });
+            }
+            Some(joined) = handshakes.join_next(), if !handshakes.is_empty() => {
+                let (conn, addr, result, pending) = match joined {
+                    Ok(handshake) => handshake,
+                    Err(e) => {
+                        tracing::error!("tcp handshake task failed: {e}");
+                        continue;
+                    }
+                };
+                let mut peer_token = None;
+                let mut negotiated_format = (AddrFormat::Full, FrameFormat::Legacy);
+                let mut negotiated_peer_flags = 0;
+                match result {
+                    Some(Ok((peer_flags, token))) => {
+                        negotiated_format = negotiate_wire_format(hello_flags, peer_flags);
+                        negotiated_peer_flags = peer_flags;
+                        peer_token = Some(token);
+                    }
+                    Some(Err(e)) => {
+                        tracing::warn!("rejecting tcp connection from {addr:?}: handshake failed: {e:#}");
+                        continue;
+                    }
+                    None => {}
+                }
+                if reject_new_connections {
+                    // Another connection may have been installed while this one was negotiating
+                    if let (Some(_), Some(active)) = (&tcp, tcp_peer) {
+                        tracing::warn!(
+                            "rejecting new tcp connection from {addr:?}, keeping active connection from {active:?}"
+                        );
+                        continue;
+                    }
+                }
+                hello_sent = hello_flags != 0;
+                (addr_format, frame_format) = negotiated_format;
+                peer_hello_flags = negotiated_peer_flags;
+                peer_goodbye = false;
+                tcp_send_queue = Some(SendQueueProbe::new(&conn));
+                let old_peer = tcp_peer.replace(addr);
+                if let (Some(_), Some(old)) = (tcp.replace(Box::new(conn)), old_peer) {
+                    tracing::warn!("new tcp connection from {addr:?} replaces old {old:?}");
+                    // The new client opens its own return path
+                    return_tcp = None;
                 } else {
                     tracing::info!("accepted incoming tcp connection from {addr:?}");
                 }
+                stats.connections += 1;
+                session_token.clear();
+                if let Some(token) = peer_token {
+                    resolve_parked_session!(token);
+                }
                 tcp_buf.clear();
+                tcp_buf_start = 0;
+                tcp_buf.extend_from_slice(&pending);
             }
             msg = tcp_fut => {
                 match msg {
                     Ok(n) => {
                         if n == 0 {
-                            tracing::warn!("TCP connection closed by remote");
+                            if peer_goodbye {
+                                tracing::info!("TCP connection closed by remote after it announced its shutdown");
+                            } else {
+                                tracing::warn!("TCP connection closed by remote");
+                            }
                             tcp = None;
-                            cleanup_flow_state!();
+                            release_flow_state!();
+                            if !listen && peer_goodbye {
+                                schedule_reconnect!();
+                            }
+                            peer_goodbye = false;
                             continue;
                         }
                     }
                     Err(e) => {
-                        tracing::error!("TCP connection error: {}", e);
+                        if peer_goodbye {
+                            tracing::info!("TCP connection error after the remote announced its shutdown: {}", e);
+                        } else {
+                            tracing::error!("TCP connection error: {}", e);
+                        }
                         tcp = None;
-                        cleanup_flow_state!();
+                        release_flow_state!();
                         if !listen {
-                            tracing::info!("Will retry TCP connection in 3 seconds...");
-                            connect_again = Some(Box::pin(tokio::time::sleep(Duration::from_secs(3))));
+                            schedule_reconnect!();
                         }
+                        peer_goodbye = false;
                         continue;
                     }
                 }
@@ -422,7 +2434,7 @@ async fn main() -> eyre::Result<()> {
                 if n == 0 {
                     tracing::warn!("TCP connection closed by remote");
                     tcp = None;
-                    cleanup_flow_state!();
+                    release_flow_state!();
                     if !listen {
                         tracing::info!("Will retry TCP connection in 3 seconds...");
                         connect_again = Some(Box::pin(tokio::time::sleep(Duration::from_secs(3))));
@@ -430,26 +2442,157 @@ async fn main() -> eyre::Result<()> {
                     continue;
                 }
 
-                let mut rest = &tcp_buf[..];
-                loop {
-                    if rest.len() < std::mem::size_of::<u32>() {
-                        break;
+                // Control replies are written after the whole buffer is processed
+                let mut hello_replies = Vec::new();
+                // Flow port reports (--flow-diagnostics) and keepalive echoes are written after the whole
+                // buffer is processed
+                let mut control_replies = Vec::new();
+
+                let mut rest = &tcp_buf[tcp_buf_start..];
+                // Decoded one frame at a time, as a Hello switches the framing of what follows it
+                // Truncated is the only error split_frame returns: the rest of the frame is still in flight
+                while let Ok((header, msg, remaining)) = split_frame(frame_format, rest) {
+                    dump_frame(dump_wire_bytes, "rx", &rest[..frame_format.header_len()], msg);
+                    rest = remaining;
+                    let frame_type = header.frame_type;
+
+                    if frame_type != FRAME_TYPE_DATA && frame_type != FRAME_TYPE_CONTROL {
+                        tracing::warn!("skipping frame of unknown type {frame_type} ({} bytes)", msg.len());
+                        continue;
                     }
-                    let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
-                    let tail = &rest[4..];
-                    if tail.len() < len {
-                        break;
+                    if frame_type == FRAME_TYPE_CONTROL {
+                        match ControlFrame::deserialize(msg) {
+                            Ok(ControlFrame::FlowPort { source, flow_port, backend }) => {
+                                let local_addr = udp.local_addr().unwrap_or_else(|_|
+                                    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+                                tracing::info!(%side, src = %source, dst = %backend, local = %local_addr, flow_port,
+                                    "flow path reported by listen side");
+                            }
+                            Ok(ControlFrame::Hello { flags, token }) => {
+                                if listen {
+                                    resolve_parked_session!(token);
+                                }
+                                // Peer initiated the handshake; answer with our own capabilities
+                                if !hello_sent {
+                                    // Replies always announce typed frame support, so the initiator decides
+                                    let reply_flags = hello_flags | HELLO_TYPED_FRAMES | HELLO_KEEPALIVE_ECHO | HELLO_FLOW_CLOSE
+                                        | HELLO_GOODBYE | HELLO_FLOW_DIAGNOSTICS;
+                                    hello_sent = true;
+                                    hello_replies.push(ControlFrame::Hello { flags: reply_flags, token: Vec::new() });
+                                    (addr_format, frame_format) = negotiate_wire_format(reply_flags, flags);
+                                    peer_hello_flags = flags;
+                                }
+                            }
+                            Ok(ControlFrame::FlowClose { source }) => {
+                                // The connect side dropped this client flow; free its socket now rather than at the idle timeout
+                                if listen && remove_flow!(source) {
+                                    tracing::info!(%side, src = %source, "flow closed by peer");
+                                }
+                            }
+                            Ok(ControlFrame::Keepalive { seq }) => {
+                                control_replies.push(ControlFrame::KeepaliveEcho { seq });
+                            }
+                            Ok(ControlFrame::Goodbye) => {
+                                // The close that follows is deliberate, not a network failure
+                                tracing::info!("Peer is shutting down");
+                                peer_goodbye = true;
+                            }
+                            Ok(ControlFrame::KeepaliveEcho { seq }) => {
+                                // Echoes of older keepalives are ignored: only the latest one is timed
+                                if let Some((sent_seq, sent_at)) = keepalive_sent {
+                                    if sent_seq == seq {
+                                        keepalive_sent = None;
+                                        let rtt = sent_at.elapsed();
+                                        stats.record_rtt(rtt);
+                                        tracing::debug!(%side, ?rtt, seq, "keepalive echoed");
+                                        if rtt_logged_at.elapsed() >= KEEPALIVE_RTT_LOG_INTERVAL {
+                                            rtt_logged_at = std::time::Instant::now();
+                                            stats.log_rtt();
+                                        }
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                tracing::error!("Failed to parse control frame from TCP stream: {e}");
+                            }
+                        }
+                        continue;
+                    }
+
+                    // Parked flows are only handed to a connection that presented the same client token
+                    if parked_session.is_some() {
+                        resolve_parked_session!(Vec::new());
                     }
-                    let msg = &tail[..len];
-                    rest = &tail[len..];
 
                     // Deserialize UDP packet with source address metadata from TCP stream
-                    if let Some(packet) = UdpPacketWithSource::deserialize(msg) {
-                        let now = SystemTime::now();
+                    if paused_since.is_some() {
+                        stats.paused_drops += 1;
+                        continue;
+                    }
+                    if !tcp_deliver {
+                        if debug {
+                            tracing::debug!("dropping data frame - this side only relays {} packets",
+                                if forward_only { "forward" } else { "return" });
+                        }
+                        continue;
+                    }
+
+                    let packet = match UdpPacketWithSource::deserialize(msg, addr_format) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            tracing::error!("Failed to parse UDP packet from TCP stream: {e}");
+                            continue;
+                        }
+                    };
+
+                    if !socket_last_activity.contains_key(&packet.source) && memory_exceeded!(FLOW_MEMORY_ESTIMATE) {
+                        stats.memory_drops += 1;
+                        tracing::warn!("dropping datagram from {} - new flow would exceed --max-memory", packet.source);
+                        continue;
+                    }
 
-                        // Calculate final destination address based on port specification mode
-                        let dest_addr = match &udp_sendto_spec {
+                    let now = Instant::now();
+
+                    // Static --route rules override the configured destination; the first matching rule
+                    // applies, resolved once per flow
+                    let route = if routes.is_empty() {
+                        None
+                    } else {
+                        *flow_routes.entry(packet.source).or_insert_with(|| {
+                            routes.iter().find(|(net, _)| net.contains(packet.source.ip())).map(|&(_, dest)| dest)
+                        })
+                    };
+
+                    // Otherwise calculate final destination address based on port specification mode
+                    let dest_addr = if let Some(dest) = route {
+                        dest
+                    } else {
+                        match &udp_sendto_spec {
                             PortSpec::Fixed(addr) => *addr,
+                            PortSpec::Pool(pool) => {
+                                // Shard mode: the backend follows the payload key, whatever flow carries it
+                                let shard_key = shard.and_then(|(offset, len)| packet.data.get(offset..offset.checked_add(len)?));
+                                let healthy = |dest: &SocketAddr| pool_health.is_healthy(dest);
+                                if let Some(key) = shard_key {
+                                    select_shard_destination(pool, key, healthy)
+                                } else {
+                                    // Pool mode: the backend is chosen once per flow and kept while it stays healthy
+                                    match flow_destinations.get(&packet.source).copied() {
+                                        Some(dest) if healthy(&dest) => dest,
+                                        previous => {
+                                            let dest = select_pool_destination(pool, &packet.source, sticky_flows, &mut pool_next, healthy);
+                                            if let Some(previous) = previous {
+                                                tracing::info!(%side, src = %packet.source, dst = %dest, previous = %previous,
+                                                    "flow moved off unhealthy pool destination");
+                                            } else if verbose {
+                                                tracing::info!(%side, src = %packet.source, dst = %dest, "pool destination assigned");
+                                            }
+                                            flow_destinations.insert(packet.source, dest);
+                                            dest
+                                        }
+                                    }
+                                }
+                            }
                             PortSpec::Auto(_) => {
                                 if listen {
                                     // Listen side: forward to the original source address from the packet
@@ -460,153 +2603,293 @@ async fn main() -> eyre::Result<()> {
                                     packet.source
                                 }
                             }
-                        };
+                        }
+                    };
 
-                        // Select appropriate UDP socket: per-flow socket in auto mode, shared socket otherwise
-                        let flow_socket = if listen && udp_bind_spec.is_auto() {
-                            // Use per-flow sockets for listen side with auto bind
-                            if !flow_sockets.contains_key(&packet.source) {
-                                // Create new UDP socket for this flow
-                                match tokio::net::UdpSocket::bind("0.0.0.0:0").await {
-                                    Ok(new_socket) => {
-                                        let local_addr = new_socket.local_addr().unwrap_or_else(|_|
-                                            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
-
-                                        if verbose {
-                                            tracing::info!("[FLOW] New UDP flow established: {} -> {} via {} (listen side)",
-                                                packet.source, dest_addr, local_addr);
-                                        }
-                                        if debug {
-                                            tracing::info!("[DEBUG] Created flow socket {} for client {} -> server {}",
-                                                local_addr, packet.source, dest_addr);
-                                        }
+                    // Only destinations derived from the packet source (IP:auto) are subject to the port offset and
+                    // allowlist; a --route destination is configured, so it is used as given
+                    let derived = route.is_none() && udp_sendto_spec.is_auto();
 
-                                        // CRITICAL: Create reverse mapping for return packets using port only
-                                        // Since flow socket binds to 0.0.0.0:port but packets come from real_ip:port,
-                                        // we use just the port number as the key for reliable matching
-                                        let port_key = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), local_addr.port());
-                                        socket_to_client.insert(port_key, packet.source);
+                    let dest_addr = if derived {
+                        match derived_destination(dest_addr, sendto_port_offset, &sendto_allow, &mut stats) {
+                            Some(dest) => dest,
+                            None => continue,
+                        }
+                    } else {
+                        dest_addr
+                    };
+
+                    // Select appropriate UDP socket: per-flow socket in auto mode, shared socket otherwise
+                    let flow_socket = if per_flow_sockets {
+                        // Use per-flow sockets for listen side with auto bind
+                        if let std::collections::hash_map::Entry::Vacant(entry) = flow_sockets.entry(packet.source) {
+                            // Out of file descriptors recently: drop new flows quietly until the cooldown ends
+                            if flow_creation_resume_at.is_some_and(|at| std::time::Instant::now() < at) {
+                                stats.fd_exhaustion_drops += 1;
+                                continue;
+                            }
+                            // Create new UDP socket for this flow. bind() assigns the ephemeral port
+                            // immediately, so the socket is fully mapped before anything is forwarded on it
+                            let bound = bind_flow_socket(flow_bind_addr, flow_port_range, &mut flow_port_next)
+                                .await
+                                .and_then(|socket| socket.local_addr().map(|local_addr| (socket, local_addr)));
+                            match bound {
+                                Ok((new_socket, local_addr)) => {
+
+                                    if verbose || debug {
+                                        tracing::info!(%side, src = %packet.source, dst = %dest_addr, local = %local_addr,
+                                            "flow socket created");
+                                    }
 
-                                        flow_sockets.insert(packet.source, new_socket);
-                                        socket_last_activity.insert(packet.source, now);
+                                    // CRITICAL: Create reverse mapping for return packets using port only
+                                    // Since flow socket binds to 0.0.0.0:port (or [::]:port) but packets come from real_ip:port,
+                                    // we use just the port number as the key for reliable matching
+                                    let port_key = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), local_addr.port());
+                                    socket_to_client.insert(port_key, packet.source);
+                                    if debug {
+                                        tracing::debug!("Created reverse mapping: port {} -> client {} (new flow)", local_addr.port(), packet.source);
                                     }
-                                    Err(e) => {
-                                        tracing::error!("Failed to create UDP socket for flow {}: {}", packet.source, e);
-                                        continue;
+                                    // Only a connect side that asked for them can read the reports
+                                    if flow_diagnostics && peer_hello_flags & HELLO_FLOW_DIAGNOSTICS != 0 {
+                                        control_replies.push(ControlFrame::FlowPort {
+                                            source: packet.source,
+                                            flow_port: local_addr.port(),
+                                            backend: dest_addr,
+                                        });
+                                    }
+
+                                    // Open NAT/firewall state toward the backend before the real datagram (--prewarm-payload)
+                                    if let Some(payload) = &prewarm_payload {
+                                        if let Err(e) = new_socket.send_to(payload, dest_addr).await {
+                                            tracing::warn!("prewarm datagram to {} for flow {} failed: {}", dest_addr, packet.source, e);
+                                        }
                                     }
+
+                                    entry.insert(new_socket);
+                                    socket_last_activity.insert(packet.source, now);
+                                }
+                                Err(e) if is_fd_exhaustion(&e) => {
+                                    stats.fd_exhaustion_drops += 1;
+                                    flow_creation_resume_at = Some(std::time::Instant::now() + FD_EXHAUSTION_COOLDOWN);
+                                    tracing::warn!("Out of file descriptors creating UDP socket for flow {} ({}); not creating new flows for {:?} \
+                                        - raise the open file limit (ulimit -n) or cap flows with --max-memory",
+                                        packet.source, e, FD_EXHAUSTION_COOLDOWN);
+                                    continue;
+                                }
+                                Err(e) => {
+                                    tracing::error!("Failed to create UDP socket for flow {}: {}", packet.source, e);
+                                    continue;
                                 }
-                            } else {
-                                // Update activity timestamp for existing socket
-                                socket_last_activity.insert(packet.source, now);
                             }
-                            flow_sockets.get(&packet.source).unwrap()
                         } else {
-                            // Non-auto modes: use the main UDP socket for all traffic
-                            &udp
-                        };
+                            // Update activity timestamp for existing socket
+                            socket_last_activity.insert(packet.source, now);
+                        }
+                        flow_sockets.get(&packet.source).unwrap()
+                    } else {
+                        // Non-auto modes: use the main UDP socket for all traffic
+                        &udp
+                    };
 
-                        // Track flow and log new flows
-                        let count = flow_packet_counts.entry(packet.source).or_insert(0);
+                    // Track flow and log new flows
+                    *flow_byte_counts.entry(packet.source).or_insert(0) += packet.data.len() as u64;
+                    let count = flow_packet_counts.entry(packet.source).or_insert(0);
 
-                        // Update activity timestamp for this flow
-                        socket_last_activity.insert(packet.source, now);
+                    // Update activity timestamp for this flow
+                    socket_last_activity.insert(packet.source, now);
 
-                        if *count == 0 && verbose {
-                            tracing::info!("[FLOW] Processing UDP flow: {} -> {} via {} ({})",
-                                packet.source, dest_addr,
-                                flow_socket.local_addr().unwrap_or_else(|_|
-                                    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)),
-                                if listen { "listen side" } else { "connect side" });
+                    if *count == 0 {
+                        stats.total_flows += 1;
+                        if let Some(exporter) = &mut netflow {
+                            exporter.flow_started(packet.source, dest_addr);
                         }
-                        *count += 1;
+                    }
+                    if *count + 1 == new_flow_threshold && verbose {
+                        let local_addr = flow_socket.local_addr().unwrap_or_else(|_|
+                            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+                        tracing::info!(%side, src = %packet.source, dst = %dest_addr, local = %local_addr, "flow established");
+                    }
+                    *count += 1;
 
-                        if debug {
-                            let socket_addr = flow_socket.local_addr().unwrap_or_else(|_|
-                                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
-                            // Use socket address as-is to avoid expensive operations
-                            tracing::info!("[DEBUG] UDP datagram #{}: {} -> {} ({} bytes) via {} ({})",
-                                count, packet.source, dest_addr, packet.data.len(), socket_addr,
-                                if listen { "listen side" } else { "connect side" });
-                        }
+                    if debug {
+                        let local_addr = flow_socket.local_addr().unwrap_or_else(|_|
+                            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+                        tracing::info!(%side, src = %packet.source, dst = %dest_addr, local = %local_addr, bytes = packet.data.len(),
+                            count = *count, "datagram forwarded");
+                    }
 
-                        // Forward UDP packet
-                        if let Err(e) = flow_socket.send_to(&packet.data, dest_addr).await {
+                    // Forward UDP packet
+                    if !per_flow_sockets {
+                        last_shared_flow = Some(packet.source);
+                    }
+                    if let Err(e) = flow_socket.send_to(&packet.data, dest_addr).await {
+                        if is_port_unreachable(&e) {
+                            // Reported for an earlier datagram on this socket
+                            for dest in record_unreachable!(flow_socket, Some(dest_addr)) {
+                                tracing::warn!("backend not listening on {} ({})", dest, e);
+                            }
+                        } else {
+                            pool_health.record_failure(&dest_addr);
                             tracing::error!("udp forward failed: {e}");
+                        }
+                    } else {
+                        if listen {
+                            stats.record_forward(packet.data.len());
                         } else {
-                            // Update reverse mapping after first packet: kernel assigns actual port only after send_to()
-                            // This enables return packet routing from server back to correct client
-                            if listen && udp_bind_spec.is_auto() && *count == 1 {
-                                let actual_local_addr = flow_socket.local_addr().unwrap_or_else(|_|
-                                    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
-                                let port_key = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), actual_local_addr.port());
-                                socket_to_client.insert(port_key, packet.source);
+                            stats.record_return(packet.data.len());
+                        }
+                    }
+                }
 
-                                if debug {
-                                    tracing::debug!("Updated reverse mapping: port {} -> client {} (new flow)", actual_local_addr.port(), packet.source);
-                                }
-                            }
+                // Hello replies use legacy framing, since the peer only switches once it has read them
+                let hello_replies = hello_replies.into_iter().map(|reply| (FrameFormat::Legacy, reply));
+                let control_replies = control_replies.into_iter().map(|reply| (frame_format, reply));
+                for (format, report) in hello_replies.chain(control_replies) {
+                    if let Some(tcp_stream) = &mut tcp {
+                        if let Err(e) = with_write_timeout(write_timeout, write_control_frame(tcp_stream, format, &report, dump_wire_bytes)).await {
+                            tracing::error!("dropping tcp connection after failed write: {e}");
+                            drop_tcp_connection!();
                         }
-                    } else {
-                        tracing::error!("Failed to parse UDP packet from TCP stream");
+                    }
+                }
+
+                // A partial frame whose declared size would blow the --max-memory budget is never
+                // buffered: a peer announcing such a frame is misbehaving, so the connection goes
+                if let Ok(header) = FrameHeader::decode(frame_format, rest) {
+                    let frame_bytes = frame_format.header_len() + header.len;
+                    if memory_exceeded!(frame_bytes) {
+                        tracing::error!("dropping tcp connection: incoming frame of {} bytes exceeds --max-memory", frame_bytes);
+                        stats.memory_drops += 1;
+                        tcp_buf.clear();
+                        tcp_buf_start = 0;
+                        drop_tcp_connection!();
+                        continue;
                     }
                 }
 
                 if rest.is_empty() {
                     tcp_buf.clear();
+                    tcp_buf_start = 0;
                 } else {
                     tracing::trace!(n = rest.len(), "bytes left over in tcp receive buffer");
-                    let keep = tcp_buf.len() - rest.len();
-                    tcp_buf.drain(..keep);
+                    tcp_buf_start = tcp_buf.len() - rest.len();
+                    // Compact only once the buffer runs out of room, instead of after every partial frame
+                    if tcp_buf.capacity() - tcp_buf.len() < TCP_BUF_MIN_SPARE {
+                        tcp_buf.drain(..tcp_buf_start);
+                        tcp_buf_start = 0;
+                    }
+                }
+            }
+            _ = parked_fut, if has_parked_session => {
+                tracing::info!("Client did not reconnect within {:?}, discarding parked flows", CLIENT_TOKEN_GRACE);
+                parked_session = None;
+                cleanup_flow_state!();
+            }
+            _ = keepalive_fut, if has_tcp && peer_hello_flags & HELLO_KEEPALIVE_ECHO != 0 => {
+                if let Some(tcp_stream) = &mut tcp {
+                    keepalive_seq = keepalive_seq.wrapping_add(1);
+                    let keepalive = ControlFrame::Keepalive { seq: keepalive_seq };
+                    if let Err(e) = with_write_timeout(write_timeout, write_control_frame(tcp_stream, frame_format, &keepalive, dump_wire_bytes)).await {
+                        tracing::error!("dropping tcp connection after failed keepalive write: {e}");
+                        drop_tcp_connection!();
+                    } else {
+                        keepalive_sent = Some((keepalive_seq, std::time::Instant::now()));
+                    }
+                }
+            }
+            _ = tokio::time::sleep(BACKPRESSURE_POLL_INTERVAL), if udp_backpressure => {
+                // Recheck the send queue at the top of the loop
+            }
+            _ = return_ready_fut, if listen && !flow_sockets.is_empty() => {
+                // Handled by the return packet poll after the select
+            }
+            _ = udp.ready(tokio::io::Interest::ERROR), if listen && cfg!(target_os = "linux") => {
+                // ICMP errors for datagrams forwarded from the shared socket
+                for dest in record_unreachable!(&udp, None) {
+                    tracing::warn!("backend not listening on {}", dest);
+                }
+            }
+            _ = udp_rebind_fut, if udp_rebind_pending => {
+                let delay = udp_rebind_retry.as_ref().map_or(UDP_REBIND_RETRY_MIN, |(_, delay)| *delay);
+                bind_udp_again!((delay * 2).min(UDP_REBIND_RETRY_MAX));
+            }
+            _ = tokio::task::yield_now(), if teardown_pending => {
+                // Next batch of sockets to close or idle flows to evict, after the select
+            }
+            _ = pause_toggle.recv() => {
+                if let Some(since) = paused_since.take() {
+                    tracing::warn!("Forwarding resumed after {}s pause, {} packets dropped while paused",
+                        since.elapsed().as_secs(), stats.paused_drops - paused_drops_at_pause);
+                } else {
+                    paused_since = Some(std::time::Instant::now());
+                    paused_drops_at_pause = stats.paused_drops;
+                    tracing::warn!("Forwarding paused - dropping traffic until resumed (send SIGUSR2 again)");
+                }
+            }
+            _ = &mut shutdown => {
+                tracing::info!("Shutdown requested, exiting");
+                // Tell the peer the close is deliberate, so it does not take it for a broken connection
+                if let Some(tcp_stream) = &mut tcp {
+                    if peer_hello_flags & HELLO_GOODBYE != 0 {
+                        let goodbye = async {
+                            write_control_frame(tcp_stream, frame_format, &ControlFrame::Goodbye, dump_wire_bytes).await?;
+                            tcp_stream.shutdown().await
+                        };
+                        if let Err(e) = with_write_timeout(Some(GOODBYE_WRITE_TIMEOUT), goodbye).await {
+                            tracing::debug!("could not send goodbye to the peer: {e}");
+                        }
+                    }
                 }
+                break;
             }
+        }
 
+        stats.peak_flows = stats.peak_flows.max(socket_last_activity.len());
 
-        }
+        // Staggered close of the sockets of released flows
+        let close_count = closing_sockets.len().min(FLOW_TEARDOWN_BATCH);
+        closing_sockets.drain(..close_count);
 
-        // Periodic cleanup: remove idle flows after 10 minutes of inactivity (listen side)
-        if listen && !flow_sockets.is_empty() {
-            let now = SystemTime::now();
+        // Periodic cleanup: remove idle flows after 10 minutes of inactivity.
+        // Both sides share this sweep, so whichever side owns flow sockets also closes them here.
+        // At most FLOW_TEARDOWN_BATCH flows are evicted per sweep; the rest wait for the next iteration.
+        idle_backlog = false;
+        if !socket_last_activity.is_empty() {
+            let now = Instant::now();
             let mut idle_flows = Vec::new();
 
             for (flow_addr, last_activity) in socket_last_activity.iter() {
-                if let Ok(duration) = now.duration_since(*last_activity) {
-                    if duration > Duration::from_secs(600) { // 10 minutes
-                        idle_flows.push(*flow_addr);
+                if now.saturating_duration_since(*last_activity) > FLOW_IDLE_TIMEOUT {
+                    if idle_flows.len() == FLOW_TEARDOWN_BATCH {
+                        idle_backlog = true;
+                        break;
                     }
+                    idle_flows.push(*flow_addr);
                 }
             }
 
             for flow_addr in idle_flows {
-                if let Some(socket) = flow_sockets.remove(&flow_addr) {
-                    // Also clean up reverse mapping
-                    if let Ok(socket_local_addr) = socket.local_addr() {
-                        socket_to_client.remove(&socket_local_addr);
-                    }
-                    socket_last_activity.remove(&flow_addr);
-                    tracing::info!("Cleaned up idle UDP socket for flow {} (idle for >10 minutes)", flow_addr);
+                // Lifetime totals of the flow, both directions
+                let packets = flow_packet_counts.get(&flow_addr).copied().unwrap_or(0);
+                let bytes = flow_byte_counts.get(&flow_addr).copied().unwrap_or(0);
+                if remove_flow!(flow_addr) {
+                    tracing::info!("Cleaned up idle UDP socket for flow {} (idle for >10 minutes, {} packets / {} bytes)",
+                        flow_addr, packets, bytes);
+                } else {
+                    tracing::info!("Cleaned up idle client flow {} (idle for >10 minutes, {} packets / {} bytes)",
+                        flow_addr, packets, bytes);
                 }
-            }
-        }
-
-        // Periodic cleanup: remove idle client flows after 10 minutes of inactivity (connect side)
-        if !listen && !socket_last_activity.is_empty() {
-            let now = SystemTime::now();
-            let mut idle_clients = Vec::new();
-
-            // Check all client flows for activity
-            for (client_addr, last_activity) in socket_last_activity.iter() {
-                if let Ok(duration) = now.duration_since(*last_activity) {
-                    if duration > Duration::from_secs(600) { // 10 minutes
-                        idle_clients.push(*client_addr);
+                // Let the listen side close the matching flow socket right away
+                if !listen && peer_hello_flags & HELLO_FLOW_CLOSE != 0 {
+                    if let Some(tcp_stream) = &mut tcp {
+                        let close = ControlFrame::FlowClose { source: flow_addr };
+                        if let Err(e) = with_write_timeout(write_timeout, write_control_frame(tcp_stream, frame_format, &close, dump_wire_bytes)).await {
+                            tracing::error!("dropping tcp connection after failed write: {e}");
+                            drop_tcp_connection!();
+                        }
                     }
                 }
             }
-
-            for client_addr in idle_clients {
-                socket_last_activity.remove(&client_addr);
-                flow_packet_counts.remove(&client_addr);
-                tracing::info!("Cleaned up idle client flow {} (idle for >10 minutes)", client_addr);
-            }
         }
 
         // Non-blocking poll of all flow sockets for return packets from server (listen side only)
@@ -614,57 +2897,88 @@ async fn main() -> eyre::Result<()> {
             if debug {
                 tracing::debug!("Polling {} flow sockets for return packets", flow_sockets.len());
             }
+            // Flows whose socket kept failing, recreated once the poll is done
+            let mut broken_flows = Vec::new();
+
             // Check each flow socket for return packets without blocking the main event loop
             for (original_client, socket) in flow_sockets.iter() {
                 let socket_local_addr = socket.local_addr().unwrap_or_else(|_|
                     SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
 
-                match socket.try_recv_from(&mut return_buf) {
+                for dest in record_unreachable!(socket, None) {
+                    tracing::warn!("backend not listening on {} for client {}", dest, original_client);
+                }
+
+                let received = socket.try_recv_from(&mut return_buf);
+                if received.is_ok() {
+                    flow_recv_errors.remove(original_client);
+                }
+                match received {
+                    Ok(_) if paused_since.is_some() => {
+                        stats.paused_drops += 1;
+                    }
                     Ok((len, from_server)) => {
+                        pool_health.record_success(&from_server);
                         // Map return packet back to original client using port-based reverse lookup
                         // Port-only key handles interface IP variations (0.0.0.0 bind vs actual interface IP)
                         let port_key = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), socket_local_addr.port());
-                        if let Some(mapped_client) = socket_to_client.get(&port_key) {
-                            if debug {
-                                tracing::info!("[DEBUG] RETURN packet received: {} bytes from {} on flow socket {} -> mapped to client {}",
-                                    len, from_server, socket_local_addr, mapped_client);
+                        // The socket belongs to `original_client`, so a missing reverse mapping is repaired from it
+                        let mapped_client = *socket_to_client.entry(port_key).or_insert_with(|| {
+                            unmapped_returns += 1;
+                            if unmapped_return_logged_at.map_or(true, |at| at.elapsed() >= UNMAPPED_RETURN_LOG_INTERVAL) {
+                                tracing::warn!("No reverse mapping for flow socket {} (return packet from {}), restored it for client {} \
+                                    ({} such packets so far)", socket_local_addr, from_server, original_client, unmapped_returns);
+                                unmapped_return_logged_at = Some(std::time::Instant::now());
                             }
+                            *original_client
+                        });
+                        if debug {
+                            tracing::info!(%side, src = %from_server, dst = %mapped_client, local = %socket_local_addr, bytes = len,
+                                "return datagram received");
+                        }
 
-                            // Package return packet with original client address for proper routing on connect side
-                            let return_packet = UdpPacketWithSource {
-                                source: *mapped_client,
-                                data: return_buf[..len].to_vec(),
-                            };
+                        // Package return packet with original client address for proper routing on connect side
+                        let return_packet = UdpPacketWithSource {
+                            source: mapped_client,
+                            data: return_buf[..len].to_vec(),
+                        };
 
-                            // Send back through TCP tunnel to connect side
-                            if let Some(tcp_stream) = &mut tcp {
-                                let serialized = return_packet.serialize();
-                                let len_bytes = (serialized.len() as u32).to_le_bytes();
-
-                                if let Err(e) = tcp_stream.write_all(&len_bytes).await {
-                                    tracing::error!("Return packet: dropping tcp connection after failed write: {e}");
-                                    tcp = None;
-                                    cleanup_flow_state!();
-                                } else if let Err(e) = tcp_stream.write_all(&serialized).await {
-                                    tracing::error!("Return packet: dropping tcp connection after failed write: {e}");
-                                    tcp = None;
-                                    cleanup_flow_state!();
-                                } else if let Err(e) = tcp_stream.flush().await {
-                                    tracing::error!("Return packet: dropping tcp connection after failed flush: {e}");
-                                    tcp = None;
-                                    cleanup_flow_state!();
-                                } else {
-                                    tracing::info!("Sent return packet {} -> {} ({} bytes) back through tunnel",
-                                        from_server, mapped_client, len);
+                        // Send back through TCP tunnel to connect side
+                        if let Some(tcp_stream) = &mut tcp {
+                            match return_packet.serialize(addr_format) {
+                                // Too long for a 16-bit length prefix; writing it would fail and cost the connection
+                                Ok(serialized) if serialized.len() > frame_format.max_body_len() => {
+                                    tracing::warn!("dropping return packet for {}: {} byte frame exceeds the {:?} framing",
+                                        mapped_client, serialized.len(), frame_format);
+                                }
+                                Ok(serialized) => {
+                                    // Dedicated return path while there is one (--return-tcp-listen)
+                                    let written = match &mut return_tcp {
+                                        Some(return_stream) => {
+                                            with_write_timeout(write_timeout, write_data_frame(return_stream, frame_format, &serialized, dump_wire_bytes)).await
+                                        }
+                                        None => with_write_timeout(write_timeout, write_data_frame(tcp_stream, frame_format, &serialized, dump_wire_bytes)).await,
+                                    };
+                                    if let Err(e) = written {
+                                        if return_tcp.take().is_some() {
+                                            tracing::error!("Return packet: dropping return tcp connection after failed write: {e}");
+                                        } else {
+                                            tracing::error!("Return packet: dropping tcp connection after failed write: {e}");
+                                            drop_tcp_connection!();
+                                        }
+                                    } else {
+                                        stats.record_return(len);
+                                        *flow_packet_counts.entry(mapped_client).or_insert(0) += 1;
+                                        *flow_byte_counts.entry(mapped_client).or_insert(0) += len as u64;
+                                        tracing::info!(%side, src = %from_server, dst = %mapped_client, bytes = len,
+                                            "return datagram sent through tunnel");
+                                    }
                                 }
+                                Err(e) => tracing::warn!("dropping return packet for {}: {e}", mapped_client),
                             }
-
-                            return_buf.resize(return_buf.capacity(), 0); // Reset buffer
-                            break; // Process one packet at a time, then continue main loop
-                        } else {
-                            tracing::error!("CRITICAL: No reverse mapping found for flow socket {} - cannot route return packet from {}",
-                                socket_local_addr, from_server);
                         }
+
+                        break; // Process one packet at a time, then continue main loop
                     }
                     Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                         // No data available on this socket, continue to next
@@ -673,13 +2987,513 @@ async fn main() -> eyre::Result<()> {
                         }
                         continue;
                     }
+                    Err(e) if is_port_unreachable(&e) => {
+                        let fallback = match (&udp_sendto_spec, flow_destinations.get(original_client)) {
+                            (_, Some(dest)) => Some(*dest),
+                            (PortSpec::Fixed(addr), None) => Some(*addr),
+                            _ => None,
+                        };
+                        let dests = record_unreachable!(socket, fallback);
+                        let backend = match dests.first() {
+                            Some(dest) => dest.to_string(),
+                            None => "unknown".to_string(),
+                        };
+                        tracing::warn!("backend not listening on {} for client {} ({})", backend, original_client, e);
+                    }
                     Err(e) => {
                         tracing::error!("Return packet recv failed on flow socket {} for client {}: {}", socket_local_addr, original_client, e);
+                        let errors = flow_recv_errors.entry(*original_client).or_insert(0);
+                        *errors += 1;
+                        if *errors >= FLOW_SOCKET_MAX_ERRORS {
+                            broken_flows.push(*original_client);
+                        }
+                    }
+                }
+            }
+
+            // Replace sockets stuck in an error state instead of waiting for the idle timeout
+            for original_client in broken_flows {
+                flow_recv_errors.remove(&original_client);
+                let Some(old_socket) = flow_sockets.remove(&original_client) else {
+                    continue;
+                };
+                if let Ok(old_local_addr) = old_socket.local_addr() {
+                    socket_to_client.remove(&SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), old_local_addr.port()));
+                }
+                drop(old_socket);
+
+                let bound = bind_flow_socket(flow_bind_addr, flow_port_range, &mut flow_port_next)
+                    .await
+                    .and_then(|socket| socket.local_addr().map(|local_addr| (socket, local_addr)));
+                match bound {
+                    Ok((new_socket, local_addr)) => {
+                        tracing::warn!("Recreated flow socket for client {} on {} after {} consecutive errors",
+                            original_client, local_addr, FLOW_SOCKET_MAX_ERRORS);
+                        let port_key = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), local_addr.port());
+                        socket_to_client.insert(port_key, original_client);
+                        flow_sockets.insert(original_client, new_socket);
+                    }
+                    Err(e) => {
+                        // Without a socket the flow is gone; the next packet from the client starts a new one
+                        tracing::error!("Failed to recreate UDP socket for flow {}: {}", original_client, e);
+                        remove_flow!(original_client);
                     }
                 }
             }
         }
+
+        // Interim records for long-running flows, then everything queued goes to the collector
+        if let Some(exporter) = &mut netflow {
+            if netflow_swept_at.elapsed() >= Duration::from_secs(1) {
+                netflow_swept_at = std::time::Instant::now();
+                for (&flow_addr, &last_activity) in &socket_last_activity {
+                    exporter.flow_active(
+                        flow_addr,
+                        flow_packet_counts.get(&flow_addr).copied().unwrap_or(0),
+                        flow_byte_counts.get(&flow_addr).copied().unwrap_or(0),
+                        wall_clock(last_activity),
+                    );
+                }
+            }
+            exporter.flush();
+        }
+    }
+
+    // Flows still open at exit end here
+    for &flow_addr in socket_last_activity.keys() {
+        export_ended_flow!(flow_addr);
+    }
+    if let Some(exporter) = &mut netflow {
+        exporter.flush();
+    }
+
+    stats.log_summary(started.elapsed());
+    pool_health.log_summary();
+    Ok(stats)
+}
+
+/// Upper bound for --udp-batch-recv; each datagram slot costs a 64 KiB buffer.
+const MAX_UDP_BATCH_RECV: usize = 256;
+
+/// Receives up to `bufs.len()` datagrams that are already queued on `socket` with a single
+/// non-blocking recvmmsg call, returning the length and source of each (in `bufs` order).
+/// Errors other than WouldBlock are logged and end the batch early.
+#[cfg(target_os = "linux")]
+fn recv_udp_batch(socket: &tokio::net::UdpSocket, bufs: &mut [Vec<u8>]) -> Vec<(usize, SocketAddr)> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: all-zero sockaddr_storage and mmsghdr values are valid (empty) C structs
+    let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { std::mem::zeroed() }; bufs.len()];
+    let mut iovecs: Vec<libc::iovec> = bufs
+        .iter_mut()
+        .map(|buf| libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(addrs.iter_mut())
+        .map(|(iov, addr)| {
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+            msg.msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    let received = socket.try_io(tokio::io::Interest::READABLE, || {
+        // SAFETY: every mmsghdr points at a live iovec/buffer and sockaddr_storage of the sizes given
+        let n = unsafe {
+            libc::recvmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as _, libc::MSG_DONTWAIT as _, std::ptr::null_mut())
+        };
+        if n < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    });
+    let n = match received {
+        Ok(n) => n,
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => 0,
+        Err(e) => {
+            tracing::debug!("recvmmsg failed: {e}");
+            0
+        }
+    };
+
+    msgs[..n]
+        .iter()
+        .zip(&addrs)
+        .filter_map(|(msg, addr)| Some((msg.msg_len as usize, sockaddr_to_std(addr)?)))
+        .collect()
+}
+
+/// Converts a source address filled in by recvmmsg. Returns None for non-IP families.
+#[cfg(target_os = "linux")]
+fn sockaddr_to_std(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match libc::c_int::from(addr.ss_family) {
+        libc::AF_INET => {
+            // SAFETY: ss_family says the storage holds a sockaddr_in
+            let addr = unsafe { &*(addr as *const libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be(addr.sin_port)))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: ss_family says the storage holds a sockaddr_in6
+            let addr = unsafe { &*(addr as *const libc::sockaddr_storage).cast::<libc::sockaddr_in6>() };
+            let ip = std::net::Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(std::net::SocketAddrV6::new(
+                ip,
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// Fallback for platforms without recvmmsg: drains queued datagrams one non-blocking read at a time.
+#[cfg(not(target_os = "linux"))]
+fn recv_udp_batch(socket: &tokio::net::UdpSocket, bufs: &mut [Vec<u8>]) -> Vec<(usize, SocketAddr)> {
+    let mut received = Vec::new();
+    for buf in bufs.iter_mut() {
+        match socket.try_recv_from(buf) {
+            Ok(datagram) => received.push(datagram),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::WouldBlock {
+                    tracing::debug!("batch recv failed: {e}");
+                }
+                break;
+            }
+        }
+    }
+    received
+}
+
+/// Binds a per-flow socket. Without a port range the OS picks any ephemeral port; with
+/// --flow-port-range each port is tried in turn (starting after the last one handed out) until
+/// a free one is found, giving up with AddrInUse once the whole range has been tried.
+/// The socket reports ICMP port unreachable from its backend (see `report_unreachable`).
+async fn bind_flow_socket(bind_addr: SocketAddr, port_range: Option<(u16, u16)>, next: &mut u16) -> std::io::Result<tokio::net::UdpSocket> {
+    let Some((low, high)) = port_range else {
+        let socket = tokio::net::UdpSocket::bind(bind_addr).await?;
+        report_unreachable(&socket);
+        return Ok(socket);
+    };
+
+    let range_len = u32::from(high - low) + 1;
+    for _ in 0..range_len {
+        let port = low + (u32::from(*next) % range_len) as u16;
+        *next = next.wrapping_add(1);
+        match tokio::net::UdpSocket::bind(SocketAddr::new(bind_addr.ip(), port)).await {
+            Ok(socket) => {
+                report_unreachable(&socket);
+                return Ok(socket);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AddrInUse,
+        format!("no free port in flow port range {low}-{high}"),
+    ))
+}
+
+/// Returns true for the errors a UDP socket reports after an ICMP port-unreachable from the
+/// peer: ConnectionRefused on connected sockets or with IP_RECVERR (Unix), ConnectionReset on Windows.
+fn is_port_unreachable(e: &std::io::Error) -> bool {
+    matches!(e.kind(), std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::ConnectionReset)
+}
+
+/// Makes an unconnected UDP socket report ICMP errors for the datagrams it sent (IP_RECVERR and,
+/// for IPv6 sockets, IPV6_RECVERR). Linux otherwise only reports them on connected sockets, and the
+/// relay's sockets stay unconnected so that replies are accepted from any address. The errors
+/// queue up on the socket and must be drained with `take_unreachable`.
+#[cfg(target_os = "linux")]
+fn report_unreachable(socket: &tokio::net::UdpSocket) {
+    use std::os::fd::AsRawFd;
+
+    let mut options = vec![(libc::SOL_IP, libc::IP_RECVERR)];
+    if socket.local_addr().is_ok_and(|addr| addr.is_ipv6()) {
+        options.push((libc::SOL_IPV6, libc::IPV6_RECVERR));
+    }
+    let on: libc::c_int = 1;
+    for (level, name) in options {
+        // SAFETY: the fd is a live socket and the option value matches its declared length
+        let ret = unsafe {
+            libc::setsockopt(socket.as_raw_fd(), level, name, (&on as *const libc::c_int).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if ret != 0 {
+            tracing::debug!("cannot enable ICMP error reports on {:?}: {}", socket.local_addr().ok(),
+                std::io::Error::last_os_error());
+        }
+    }
+}
+
+/// Elsewhere unconnected sockets either report ICMP errors already (Windows) or not at all.
+#[cfg(not(target_os = "linux"))]
+fn report_unreachable(_socket: &tokio::net::UdpSocket) {}
+
+/// Drains the ICMP errors queued by `report_unreachable` and returns the destinations that
+/// answered with port unreachable, one entry per error. The queue is read through the socket's
+/// error readiness, so waiting on `ready(Interest::ERROR)` works again once it is empty.
+#[cfg(target_os = "linux")]
+fn take_unreachable(socket: &tokio::net::UdpSocket) -> Vec<SocketAddr> {
+    use std::os::fd::AsRawFd;
+
+    let mut unreachable = Vec::new();
+    // Ok(None) is an error other than port unreachable, which is skipped
+    while let Ok(dest) = socket.try_io(tokio::io::Interest::ERROR, || {
+        // SAFETY: all-zero sockaddr_storage and msghdr values are valid (empty) C structs
+        let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        // The returned copy of the offending datagram is not needed, only its destination
+        let mut data = [0u8; 1];
+        let mut iov = libc::iovec { iov_base: data.as_mut_ptr().cast(), iov_len: data.len() };
+        let mut control = [0u64; 64];
+        msg.msg_name = (&mut addr as *mut libc::sockaddr_storage).cast();
+        msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+        // SAFETY: msg points at live buffers of the sizes given
+        let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut refused = false;
+        // SAFETY: the control buffer was filled in by recvmsg and msg_controllen bounds it
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            // SAFETY: CMSG_FIRSTHDR/CMSG_NXTHDR only return headers inside the control buffer
+            let header = unsafe { &*cmsg };
+            if (header.cmsg_level, header.cmsg_type) == (libc::SOL_IP, libc::IP_RECVERR)
+                || (header.cmsg_level, header.cmsg_type) == (libc::SOL_IPV6, libc::IPV6_RECVERR)
+            {
+                // SAFETY: IP_RECVERR/IPV6_RECVERR messages start with a sock_extended_err
+                let err = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<libc::sock_extended_err>()) };
+                refused |= err.ee_errno == libc::ECONNREFUSED as u32;
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+        // IPv6 sockets report IPv4 destinations in their mapped form
+        Ok(sockaddr_to_std(&addr).filter(|_| refused).map(|dest| match dest.ip() {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(dest, |ip| SocketAddr::new(IpAddr::V4(ip), dest.port())),
+            IpAddr::V4(_) => dest,
+        }))
+    }) {
+        unreachable.extend(dest);
+    }
+    unreachable
+}
+
+#[cfg(not(target_os = "linux"))]
+fn take_unreachable(_socket: &tokio::net::UdpSocket) -> Vec<SocketAddr> {
+    Vec::new()
+}
+
+/// Returns true when the process or the system ran out of file descriptors: EMFILE or ENFILE on
+/// Unix, WSAEMFILE on Windows.
+#[cfg(unix)]
+fn is_fd_exhaustion(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
+#[cfg(not(unix))]
+fn is_fd_exhaustion(e: &std::io::Error) -> bool {
+    cfg!(windows) && e.raw_os_error() == Some(10024)
+}
+
+/// Applies --sendto-port-offset and --sendto-allow to a destination derived from a packet's source
+/// address (--udp-sendto IP:auto). Returns None, after logging why, if the datagram must be dropped.
+fn derived_destination(dest: SocketAddr, port_offset: i32, allow: &[IpNet], stats: &mut LifetimeStats) -> Option<SocketAddr> {
+    // Auto-derived destinations may sit at a fixed port distance from the source
+    let dest = if port_offset != 0 {
+        match u16::try_from(i32::from(dest.port()) + port_offset) {
+            Ok(port) if port != 0 => SocketAddr::new(dest.ip(), port),
+            _ => {
+                tracing::warn!("dropping datagram for {} - port offset {} leaves the valid port range", dest, port_offset);
+                return None;
+            }
+        }
+    } else {
+        dest
+    };
+    // They come from the peer, so they must stay inside the allowlist
+    if !allow.is_empty() && !allow.iter().any(|net| net.contains(dest.ip())) {
+        stats.sendto_denied += 1;
+        tracing::warn!("dropping datagram for {} - destination not allowed by --sendto-allow", dest);
+        return None;
+    }
+    Some(dest)
+}
+
+/// Picks the pool destination for a new flow.
+/// Round-robin by default; with `sticky` the source address is hashed so a client that
+/// reappears after cleanup or a restart lands on the same backend again. Destinations that
+/// `healthy` rejects are skipped in favour of the next one in the pool.
+fn select_pool_destination(
+    pool: &[SocketAddr],
+    source: &SocketAddr,
+    sticky: bool,
+    next: &mut usize,
+    healthy: impl Fn(&SocketAddr) -> bool,
+) -> SocketAddr {
+    let index = if sticky {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        source.hash(&mut hasher);
+        (hasher.finish() % pool.len() as u64) as usize
+    } else {
+        let index = *next % pool.len();
+        *next = next.wrapping_add(1);
+        index
+    };
+    first_healthy(pool, index, healthy)
+}
+
+/// Returns the first healthy pool destination starting at `start`, or the one at `start` when
+/// none is healthy, so traffic still flows (and probes) while the whole pool is down.
+fn first_healthy(pool: &[SocketAddr], start: usize, healthy: impl Fn(&SocketAddr) -> bool) -> SocketAddr {
+    (0..pool.len())
+        .map(|step| pool[(start + step) % pool.len()])
+        .find(|dest| healthy(dest))
+        .unwrap_or(pool[start])
+}
+
+/// Picks the pool destination for a datagram in shard mode by hashing its payload key, so every
+/// datagram with the same key reaches the same backend regardless of its source (or the next
+/// healthy one while it is down).
+fn select_shard_destination(pool: &[SocketAddr], key: &[u8], healthy: impl Fn(&SocketAddr) -> bool) -> SocketAddr {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+    first_healthy(pool, (hasher.finish() % pool.len() as u64) as usize, healthy)
+}
+
+/// Binds the TCP listener, optionally with an explicit accept backlog.
+/// Without a backlog the platform default from `TcpListener::bind` is used; with one the socket
+/// is built by hand so `listen(backlog)` can be called with the requested queue length.
+async fn bind_tcp_listener(addr: SocketAddr, backlog: Option<u32>) -> std::io::Result<tokio::net::TcpListener> {
+    let Some(backlog) = backlog else {
+        return tokio::net::TcpListener::bind(addr).await;
+    };
+
+    let socket = if addr.is_ipv4() {
+        tokio::net::TcpSocket::new_v4()?
+    } else {
+        tokio::net::TcpSocket::new_v6()?
+    };
+    // Match TcpListener::bind, which enables SO_REUSEADDR on Unix so restarts don't hit TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    tracing::debug!("listening on {:?} with accept backlog {}", addr, backlog);
+    socket.listen(backlog)
+}
+
+/// Identity the process switches to once its sockets are bound (--user/--group).
+#[derive(Debug, Clone, Copy)]
+struct RunAs {
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+/// Resolves --user/--group, given as names or numeric IDs. Without --group the user's primary
+/// group is used, so a process started as root keeps no root group either; a numeric --user
+/// with no passwd entry has no primary group and needs --group.
+#[cfg(unix)]
+fn resolve_run_as(user: Option<&str>, group: Option<&str>) -> eyre::Result<RunAs> {
+    let mut run_as = RunAs { uid: None, gid: None };
+    if let Some(user) = user {
+        let name = std::ffi::CString::new(user).wrap_err("--user")?;
+        // SAFETY: getpwnam is given a valid C string; the entry is copied out before the next call
+        let mut entry = unsafe { libc::getpwnam(name.as_ptr()) };
+        if entry.is_null() {
+            let uid = user.parse::<u32>().map_err(|_| eyre::eyre!("--user: no such user '{}'", user))?;
+            run_as.uid = Some(uid);
+            // SAFETY: as for getpwnam above
+            entry = unsafe { libc::getpwuid(uid) };
+        }
+        if !entry.is_null() {
+            // SAFETY: non-null entries returned by getpwnam/getpwuid point to a valid passwd struct
+            let entry = unsafe { &*entry };
+            run_as.uid = Some(entry.pw_uid);
+            run_as.gid = Some(entry.pw_gid);
+        } else if group.is_none() {
+            eyre::bail!("--user: uid {} has no passwd entry to take a primary group from, pass --group too", user);
+        }
+    }
+    if let Some(group) = group {
+        let name = std::ffi::CString::new(group).wrap_err("--group")?;
+        // SAFETY: as for getpwnam above
+        let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+        if entry.is_null() {
+            let gid = group.parse::<u32>().map_err(|_| eyre::eyre!("--group: no such group '{}'", group))?;
+            run_as.gid = Some(gid);
+        } else {
+            // SAFETY: non-null entries returned by getgrnam point to a valid group struct
+            run_as.gid = Some(unsafe { (*entry).gr_gid });
+        }
+    }
+    Ok(run_as)
+}
+
+#[cfg(not(unix))]
+fn resolve_run_as(_user: Option<&str>, _group: Option<&str>) -> eyre::Result<RunAs> {
+    eyre::bail!("--user/--group are only supported on Unix")
+}
+
+/// Drops root privileges to `run_as`: supplementary groups, then the group, then the user, so
+/// each step still has the rights it needs. Any failure is an error; the process never keeps
+/// running as root after --user was asked for. While still root the supplementary groups are
+/// always replaced, even without a group to switch to, so none of root's are kept.
+#[cfg(unix)]
+fn drop_privileges(run_as: RunAs) -> eyre::Result<()> {
+    // SAFETY: plain syscalls on process credentials, no pointers besides the one-element group list
+    unsafe {
+        if libc::geteuid() == 0 {
+            let result = match &run_as.gid {
+                Some(gid) => libc::setgroups(1, gid),
+                None => libc::setgroups(0, std::ptr::null()),
+            };
+            if result != 0 {
+                return Err(std::io::Error::last_os_error()).wrap_err("setgroups");
+            }
+        }
+    }
+    if let Some(gid) = run_as.gid {
+        // SAFETY: as above
+        unsafe {
+            if libc::setgid(gid) != 0 {
+                return Err(std::io::Error::last_os_error()).wrap_err_with(|| format!("setgid({gid})"));
+            }
+        }
+    }
+    if let Some(uid) = run_as.uid {
+        // SAFETY: as above
+        unsafe {
+            if libc::setuid(uid) != 0 {
+                return Err(std::io::Error::last_os_error()).wrap_err_with(|| format!("setuid({uid})"));
+            }
+            if uid != 0 && libc::setuid(0) == 0 {
+                eyre::bail!("setuid({uid}) succeeded but root privileges could be regained");
+            }
+        }
     }
+    let describe = |id: Option<u32>| id.map_or_else(|| "unchanged".to_string(), |id| id.to_string());
+    tracing::info!("dropped privileges to uid {}, gid {}", describe(run_as.uid), describe(run_as.gid));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn drop_privileges(_run_as: RunAs) -> eyre::Result<()> {
+    unreachable!("resolve_run_as rejects --user/--group outside Unix")
 }
 
 /// Displays comprehensive help information and exits with the specified code.
@@ -702,23 +3516,73 @@ fn usage(exit_with: i32) -> ! {
     eprintln!("USAGE:");
     eprintln!("    {bin} [OPTIONS] --tcp-listen <PORT> --udp-bind <ADDR> --udp-sendto <ADDR>");
     eprintln!("    {bin} [OPTIONS] --tcp-connect <ADDR> --udp-bind <ADDR> --udp-sendto <ADDR>");
+    eprintln!("    {bin} [OPTIONS] --loopback --udp-bind <ADDR> --udp-sendto <ADDR>");
     eprintln!();
     eprintln!("REQUIRED ARGUMENTS:");
-    eprintln!("    --tcp-listen <PORT>     Listen for TCP connections on this port");
+    eprintln!("    --tcp-listen <PORT>     Listen for TCP connections on this port (repeatable for more addresses)");
     eprintln!("    --tcp-connect <ADDR>    Connect to TCP server at this address");
+    eprintln!("    --loopback              Run both sides in this process over an in-memory pipe (testing, demos)");
     eprintln!("    --udp-bind <ADDR>       Bind UDP socket to this address (use 'auto' for per-flow)");
     eprintln!("    --udp-sendto <ADDR>     Forward UDP packets to this address (use 'IP:auto' for dynamic)");
+    eprintln!("    --udp-sendto-roundrobin <ADDR,ADDR,...>");
+    eprintln!("                            Instead of --udp-sendto: assign each new flow one backend round-robin");
     eprintln!();
     eprintln!("OPTIONS:");
+    eprintln!("    --tcp-listen-backlog <N> Accept queue length for --tcp-listen (default: OS default)");
+    eprintln!("    --return-tcp-listen <PORT> Accept a second TCP connection that carries only return packets");
+    eprintln!("    --return-tcp-connect <ADDR> Open that second connection to the listen side's --return-tcp-listen");
+    eprintln!("    --forward-only          Relay only connect-to-listen packets: no return path, no per-flow sockets");
+    eprintln!("    --return-only           Relay only listen-to-connect packets (fixed --udp-bind)");
+    eprintln!("    --shard-offset <N> --shard-len <N>");
+    eprintln!("                            Choose the pool backend per datagram by hashing these payload bytes");
+    eprintln!("    --route <CIDR>=<ADDR>   Send flows from sources in CIDR to ADDR instead (repeatable, first match wins)");
+    eprintln!("    --sticky-flows          Assign pool backends by hashing the source address instead of round-robin");
+    eprintln!("    --flow-port-range <LOW-HIGH>");
+    eprintln!("                            Bind auto-mode flow sockets only to ports in this range (listen side)");
+    eprintln!("    --udp-sendto-source-port <PORT>");
+    eprintln!("                            Source port of datagrams sent to a fixed --udp-sendto, i.e. the port of");
+    eprintln!("                            a fixed --udp-bind (which must then be 0 or the same; listen side)");
+    eprintln!("    --netflow <ADDR>        Export NetFlow v5 records of ended and long-running IPv4 flows to this collector");
+    eprintln!("    --match-prefix <HEX>    Only tunnel datagrams whose payload starts with HEX (repeatable, any match; connect side)");
+    eprintln!("    --prewarm-payload <HEX> Send this datagram (\"\" for empty) to the backend when a flow socket is created");
+    eprintln!("    --sendto-port-offset <N> Add a signed offset to the port of auto-derived destinations");
+    eprintln!("    --sendto-allow <CIDR>   Only forward to auto-derived destinations inside this network (repeatable)");
+    eprintln!("    --ipv4-only             Use the compact 6-byte IPv4 source header when both sides agree");
+    eprintln!("    --no-source-header      Send raw payloads without a source header when both sides agree (fixed addresses only)");
+    eprintln!("    --typed-frames          Negotiate a frame type byte after each length prefix (falls back if unsupported)");
+    eprintln!("    --frame-len-width <16|32> Length prefix width; 16 implies --typed-frames, falls back to 32 if unsupported");
+    eprintln!("    --flow-diagnostics      Report assigned flow ports to the connect side (listen side, auto bind);");
+    eprintln!("                            the connect side needs it too, to ask for and log the reports");
+    eprintln!("    --client-token <TOKEN>  Identify this client so the listen side keeps its flows for 60s across reconnects");
+    eprintln!("    --reject-new-connections Keep the active TCP connection and refuse new ones (listen side)");
+    eprintln!("    --udp-batch-recv <N>    Read up to N queued datagrams per wakeup (recvmmsg on Linux; default: 1)");
+    eprintln!("    --udp-rebind-after <N>  Rebind the UDP socket after N receive errors in a row (default: 10)");
+    eprintln!("    --max-memory <BYTES>    Soft cap on buffered bytes and flow state; excess frames/flows are dropped");
+    eprintln!("    --tx-high-water <BYTES> Stop reading UDP while this much is unsent in the TCP send buffer (Linux)");
+    eprintln!("    --tx-low-water <BYTES>  Resume reading UDP once the send buffer drains to this (default: half)");
+    eprintln!("    --user <NAME|UID>       Switch to this user once the UDP and TCP sockets are bound (Unix)");
+    eprintln!("    --group <NAME|GID>      Switch to this group (default with --user: the user's primary group;");
+    eprintln!("                            required with a numeric --user that has no passwd entry)");
+    eprintln!("    --handshake-timeout <SECS> Drop new connections whose Hello exchange takes longer (default: 10)");
+    eprintln!("    --keepalive-interval <SECS> Send a keepalive this often and log the round-trip time of its echo");
+    eprintln!("    --write-timeout <SECS>  Drop the TCP connection if a write stalls this long (default: no limit)");
+    eprintln!("    --probe                 Connect, complete the handshake and exit (0 = reachable); connect side only");
+    eprintln!("    --probe-echo            With --probe: also check that a marker datagram is echoed back by the backend");
+    eprintln!("    --dump-wire             Hex-dump every frame sent and received (HIGH VOLUME, protocol debugging)");
+    eprintln!("    --dump-wire-bytes <N>   Bytes of each frame shown by --dump-wire (default: 64)");
     eprintln!("    -v, --verbose           Enable verbose flow logging");
     eprintln!("    --debug                 Enable debug logging with packet details");
+    eprintln!("    --new-flow-threshold <N> Log a flow as established only once it has N packets (default: 1)");
+    eprintln!("    -q, --quiet             Only log errors, even if RUST_LOG asks for more");
+    eprintln!("    --log-file <PATH>       Write logs to PATH instead of stderr, rotating at 10 MiB (keeps 3 old files)");
     eprintln!("    -h, --help              Show this help message");
     eprintln!();
     eprintln!("ADDRESS FORMATS:");
     eprintln!("    PORT                    Port number (uses default IP: 0.0.0.0 for bind, 127.0.0.1 for connect)");
     eprintln!("    IP:PORT                 Explicit IP address and port");
     eprintln!("    auto                    Dynamic per-flow sockets (--udp-bind only, listen side only)");
-    eprintln!("    IP:auto                 Dynamic destination port (--udp-sendto only, connect side only)");
+    eprintln!("    IP:auto                 --udp-bind: per-flow sockets bound to IP, e.g. [::]:auto for IPv6");
+    eprintln!("                            --udp-sendto: dynamic destination port (connect side only)");
     eprintln!();
     eprintln!("AUTO MODE:");
     eprintln!("    The 'auto' keyword enables advanced per-flow socket management:");
@@ -748,9 +3612,19 @@ fn usage(exit_with: i32) -> ! {
     eprintln!("    # Connect side - dynamic return routing");
     eprintln!("    {bin} --tcp-connect server:7878 --udp-bind 127.0.0.1:9999 --udp-sendto 192.168.1.100:auto");
     eprintln!();
+    eprintln!("  Deployment check (exit code reports the result):");
+    eprintln!("    {bin} --tcp-connect server:7878 --probe --probe-echo");
+    eprintln!();
+    eprintln!("  Both sides in one process, no network setup (testing):");
+    eprintln!("    {bin} --loopback --udp-bind 127.0.0.1:8888 --udp-sendto 127.0.0.1:9999");
+    eprintln!();
     eprintln!("  With verbose logging:");
     eprintln!("    {bin} --tcp-listen 7878 --udp-bind auto --udp-sendto 192.168.1.100:9999 --verbose");
     eprintln!();
+    eprintln!("MAINTENANCE:");
+    eprintln!("    Send SIGUSR2 to pause forwarding (traffic is dropped, TCP connection and flows are kept)");
+    eprintln!("    and SIGUSR2 again to resume. Unix only.");
+    eprintln!();
     eprintln!("FLOW MANAGEMENT:");
     eprintln!("    - Each client gets a dedicated UDP socket (auto mode)");
     eprintln!("    - Flow tables track client mappings for return packets");
@@ -774,9 +3648,75 @@ fn port_or_addr(arg: OsString, default_addr: Ipv4Addr) -> eyre::Result<SocketAdd
     }
 }
 
+/// Parses a "LOW-HIGH" port range (inclusive) for --flow-port-range.
+fn parse_port_range(arg: OsString) -> eyre::Result<(u16, u16)> {
+    let arg_str = arg.to_string_lossy();
+    let Some((low, high)) = arg_str.split_once('-') else {
+        eyre::bail!("expected LOW-HIGH");
+    };
+    let low: u16 = low.trim().parse().map_err(|_| eyre::eyre!("invalid port: {}", low))?;
+    let high: u16 = high.trim().parse().map_err(|_| eyre::eyre!("invalid port: {}", high))?;
+    if low == 0 || low > high {
+        eyre::bail!("port range must satisfy 0 < LOW <= HIGH");
+    }
+    Ok((low, high))
+}
+
+/// Parses a hex string such as "deadbeef" for --prewarm-payload; an empty string is an empty payload.
+fn parse_hex(arg: OsString) -> eyre::Result<Vec<u8>> {
+    let Some(hex) = arg.to_str() else {
+        eyre::bail!("value is not valid UTF-8");
+    };
+    if hex.len() % 2 != 0 {
+        eyre::bail!("hex string must have an even number of digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).wrap_err_with(|| format!("invalid hex byte '{}'", &hex[i..i + 2])))
+        .collect()
+}
+
+/// Parses a "SRC_CIDR=DEST_ADDR" rule for --route.
+fn parse_route(arg: OsString) -> eyre::Result<(IpNet, SocketAddr)> {
+    let arg_str = arg.to_string_lossy();
+    let Some((net, dest)) = arg_str.split_once('=') else {
+        eyre::bail!("expected SRC_CIDR=DEST_ADDR");
+    };
+    let net = parse_ip_net(net.into()).wrap_err("source network")?;
+    let dest = port_or_addr(dest.into(), Ipv4Addr::LOCALHOST).wrap_err("destination")?;
+    Ok((net, dest))
+}
+
+/// Parses an "IP/PREFIX" network for --sendto-allow. A bare IP is a single-host network.
+fn parse_ip_net(arg: OsString) -> eyre::Result<IpNet> {
+    let arg_str = arg.to_string_lossy();
+    let (ip_str, prefix_str) = match arg_str.split_once('/') {
+        Some((ip_str, prefix_str)) => (ip_str, Some(prefix_str)),
+        None => (&*arg_str, None),
+    };
+    let addr: IpAddr = ip_str.parse().map_err(|_| eyre::eyre!("invalid IP address: {}", ip_str))?;
+    let max_len = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match prefix_str {
+        Some(prefix_str) => prefix_str.parse::<u8>().map_err(|_| eyre::eyre!("invalid prefix length: {}", prefix_str))?,
+        None => max_len,
+    };
+    if prefix_len > max_len {
+        eyre::bail!("prefix length must be at most {}", max_len);
+    }
+    Ok(IpNet { addr, prefix_len })
+}
+
+/// Parses a comma-separated list of addresses (each `PORT` or `IP:PORT`) for destination pools.
+fn parse_addr_list(arg: OsString, default_addr: Ipv4Addr) -> eyre::Result<Vec<SocketAddr>> {
+    arg.to_string_lossy()
+        .split(',')
+        .map(|item| port_or_addr(OsString::from(item.trim()), default_addr))
+        .collect()
+}
+
 /// Parses a port specification that can be:
 /// - "auto" -> Auto mode with default IP
-/// - "IP:auto" -> Auto mode with specific IP
+/// - "IP:auto" -> Auto mode with specific IP (IPv6 may be bracketed, e.g. "[::]:auto")
 /// - "PORT" or "IP:PORT" -> Fixed address mode
 fn parse_port_spec(arg: OsString, default_addr: Ipv4Addr) -> eyre::Result<PortSpec> {
     let arg_str = arg.to_string_lossy();
@@ -786,8 +3726,9 @@ fn parse_port_spec(arg: OsString, default_addr: Ipv4Addr) -> eyre::Result<PortSp
     }
 
     // Check for IP:auto format
-    if let Some((ip_str, port_str)) = arg_str.split_once(':') {
+    if let Some((ip_str, port_str)) = arg_str.rsplit_once(':') {
         if port_str == "auto" {
+            let ip_str = ip_str.trim_start_matches('[').trim_end_matches(']');
             let ip: IpAddr = ip_str.parse()
                 .map_err(|_| eyre::eyre!("invalid IP address: {}", ip_str))?;
             return Ok(PortSpec::Auto(ip));
@@ -800,3 +3741,249 @@ fn parse_port_spec(arg: OsString, default_addr: Ipv4Addr) -> eyre::Result<PortSp
         Err(e) => Err(e),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Options of the listen half of --loopback: per-flow sockets on 127.0.0.1, forwarding to `backend`.
+    fn loopback_listen_options(backend: SocketAddr) -> RelayOptions {
+        RelayOptions {
+            listen: true,
+            loopback: true,
+            tcp_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+            extra_tcp_listen: Vec::new(),
+            return_tcp_addr: None,
+            udp_bind_spec: PortSpec::Auto(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            udp_sendto_spec: PortSpec::Fixed(backend),
+            verbose: false,
+            debug: false,
+            tcp_listen_backlog: None,
+            flow_diagnostics: false,
+            sticky_flows: false,
+            forward_only: false,
+            return_only: false,
+            shard: None,
+            flow_port_range: None,
+            prewarm_payload: None,
+            sendto_allow: Vec::new(),
+            routes: Vec::new(),
+            match_prefixes: Vec::new(),
+            netflow: None,
+            sendto_port_offset: 0,
+            write_timeout: None,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            keepalive_interval: None,
+            run_as: None,
+            udp_batch_recv: 1,
+            udp_rebind_after: UDP_REBIND_AFTER_ERRORS,
+            new_flow_threshold: 1,
+            max_memory: None,
+            tx_watermarks: None,
+            reject_new_connections: false,
+            hello_flags: 0,
+            client_token: Vec::new(),
+            dump_wire_bytes: 0,
+        }
+    }
+
+    /// Appends a legacy data frame carrying `data` from `source`, as the connect side sends it.
+    fn push_datagram(stream: &mut Vec<u8>, source: SocketAddr, data: &[u8]) {
+        let packet = UdpPacketWithSource { source, data: data.to_vec() };
+        encode_frame(FrameFormat::Legacy, FRAME_TYPE_DATA, &packet.serialize(AddrFormat::Full).unwrap(), stream).unwrap();
+    }
+
+    /// Fresh directory for a log rotation test, removed first if an earlier run left it behind.
+    fn log_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("udp-over-tcp-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read(path: std::path::PathBuf) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn log_file_rotates_at_size_limit() {
+        use std::io::Write;
+        let dir = log_dir("rotate");
+        let path = dir.join("relay.log");
+        let mut log = RotatingLogFile::open(path.clone(), 10, 3).unwrap();
+        log.write_all(b"aaaaaaa\n").unwrap();
+        log.write_all(b"b\n").unwrap();
+        assert!(!dir.join("relay.log.1").exists(), "rotated before reaching the limit");
+        // Exactly 10 bytes written; anything more starts a new file
+        log.write_all(b"c\n").unwrap();
+        assert_eq!(read(dir.join("relay.log.1")), "aaaaaaa\nb\n");
+        assert_eq!(read(path), "c\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_file_shifts_and_caps_rotated_files() {
+        use std::io::Write;
+        let dir = log_dir("keep");
+        let path = dir.join("relay.log");
+        let mut log = RotatingLogFile::open(path.clone(), 4, 2).unwrap();
+        for line in ["one\n", "two\n", "six\n", "ten\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(read(path), "ten\n");
+        assert_eq!(read(dir.join("relay.log.1")), "six\n");
+        assert_eq!(read(dir.join("relay.log.2")), "two\n");
+        assert!(!dir.join("relay.log.3").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_file_keeps_logging_when_rotation_fails() {
+        use std::io::Write;
+        let dir = log_dir("fail");
+        let path = dir.join("relay.log");
+        // A non-empty directory in the way of PATH.1 makes the rename fail
+        std::fs::create_dir_all(dir.join("relay.log.1").join("blocker")).unwrap();
+        let mut log = RotatingLogFile::open(path.clone(), 4, 1).unwrap();
+        log.write_all(b"one\n").unwrap();
+        log.write_all(b"two\n").unwrap();
+        log.write_all(b"six\n").unwrap();
+        assert_eq!(read(path), "one\ntwo\nsix\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn client(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), port)
+    }
+
+    #[tokio::test]
+    async fn idle_flow_releases_its_socket() {
+        let backend = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (mut connect_end, listen_end) = tokio::io::duplex(LOOPBACK_PIPE_CAPACITY);
+        let relay = run_relay(loopback_listen_options(backend.local_addr().unwrap()), Some(Box::new(listen_end)));
+
+        let test = async {
+            let mut frames = Vec::new();
+            push_datagram(&mut frames, client(5000), b"first");
+            connect_end.write_all(&frames).await.unwrap();
+            let mut buf = [0; 64];
+            let (_, flow_addr) = backend.recv_from(&mut buf).await.unwrap();
+            assert!(std::net::UdpSocket::bind(flow_addr).is_err(), "flow socket should be open");
+
+            tokio::time::pause();
+            tokio::time::advance(FLOW_IDLE_TIMEOUT + Duration::from_secs(1)).await;
+            // The sweep runs once per loop iteration, so another flow's datagram drives it
+            frames.clear();
+            push_datagram(&mut frames, client(5001), b"second");
+            connect_end.write_all(&frames).await.unwrap();
+            let (n, _) = backend.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"second");
+
+            // Released sockets are closed in batches over the following iterations
+            for _ in 0..100 {
+                if std::net::UdpSocket::bind(flow_addr).is_ok() {
+                    return;
+                }
+                tokio::task::yield_now().await;
+            }
+            panic!("flow socket {flow_addr} still open after the idle timeout");
+        };
+        select! {
+            result = relay => panic!("relay stopped: {result:?}"),
+            _ = test => {}
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn closed_backend_port_is_counted() {
+        // Nothing listens on the port once the socket that reserved it is gone
+        let backend = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (mut connect_end, listen_end) = tokio::io::duplex(LOOPBACK_PIPE_CAPACITY);
+        let relay = run_relay(loopback_listen_options(backend), Some(Box::new(listen_end)));
+
+        let test = async {
+            let mut frames = Vec::new();
+            push_datagram(&mut frames, client(5000), b"one");
+            push_datagram(&mut frames, client(5001), b"two");
+            connect_end.write_all(&frames).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            // Closing the pipe stops the relay, which then returns its totals
+            drop(connect_end);
+        };
+        let (stats, ()) = tokio::join!(relay, test);
+        assert_eq!(stats.unwrap().backend_unreachable, 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn dead_pool_member_gets_no_new_flows() {
+        let dead = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let alive = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let options = RelayOptions {
+            udp_sendto_spec: PortSpec::Pool(vec![dead, alive.local_addr().unwrap()]),
+            ..loopback_listen_options(dead)
+        };
+        let (mut connect_end, listen_end) = tokio::io::duplex(LOOPBACK_PIPE_CAPACITY);
+        let relay = run_relay(options, Some(Box::new(listen_end)));
+
+        let test = async {
+            // Round robin until the dead member has failed BACKEND_MAX_FAILURES times, then only the live one
+            let flows = 2 * BACKEND_MAX_FAILURES as u16 + 4;
+            for port in 5000..5000 + flows {
+                let mut frames = Vec::new();
+                push_datagram(&mut frames, client(port), &port.to_le_bytes());
+                connect_end.write_all(&frames).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            let mut received = Vec::new();
+            let mut buf = [0; 64];
+            while let Ok(Ok((n, _))) = tokio::time::timeout(Duration::from_millis(200), alive.recv_from(&mut buf)).await {
+                received.push(u16::from_le_bytes([buf[0], buf[1]]));
+                assert_eq!(n, 2);
+            }
+            let expected: Vec<u16> = (5000..5000 + flows)
+                .enumerate()
+                .filter(|&(i, _)| i % 2 == 1 || i >= 2 * BACKEND_MAX_FAILURES as usize)
+                .map(|(_, port)| port)
+                .collect();
+            assert_eq!(received, expected);
+            drop(connect_end);
+        };
+        let (stats, ()) = tokio::join!(relay, test);
+        assert_eq!(stats.unwrap().backend_unreachable, u64::from(BACKEND_MAX_FAILURES));
+    }
+
+    #[tokio::test]
+    async fn back_to_back_datagrams_share_one_flow_socket() {
+        let backend = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (mut connect_end, listen_end) = tokio::io::duplex(LOOPBACK_PIPE_CAPACITY);
+        let relay = run_relay(loopback_listen_options(backend.local_addr().unwrap()), Some(Box::new(listen_end)));
+
+        let test = async {
+            // Both frames arrive in one read, so they are handled in the same pass over the buffer
+            let mut frames = Vec::new();
+            push_datagram(&mut frames, client(5000), b"one");
+            push_datagram(&mut frames, client(5000), b"two");
+            connect_end.write_all(&frames).await.unwrap();
+
+            let mut buf = [0; 64];
+            let (n, first_from) = backend.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"one");
+            let (n, second_from) = backend.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"two");
+            assert_eq!(first_from, second_from);
+
+            // A reply to that socket goes back to the original client
+            backend.send_to(b"reply", first_from).await.unwrap();
+            let (frame_type, body) = read_frame(&mut connect_end, FrameFormat::Legacy, 0).await.unwrap();
+            assert_eq!(frame_type, FRAME_TYPE_DATA);
+            let packet = UdpPacketWithSource::deserialize(&body, AddrFormat::Full).unwrap();
+            assert_eq!((packet.source, &packet.data[..]), (client(5000), &b"reply"[..]));
+        };
+        select! {
+            result = relay => panic!("relay stopped: {result:?}"),
+            _ = test => {}
+        }
+    }
+}
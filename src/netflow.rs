@@ -0,0 +1,308 @@
+//! NetFlow v5 export of tunnel flows to a collector (--netflow).
+//!
+//! An export datagram is a 24-byte header followed by up to [`MAX_RECORDS`] records of 48 bytes,
+//! all integers big-endian. Flow start and end times are milliseconds of exporter uptime, taken
+//! from the header's `sys_uptime` and wall clock. v5 only carries IPv4 addresses, so flows with an
+//! IPv6 end (other than IPv4-mapped ones) are not exported.
+//!
+//! Packet and byte counts are those of the relay's flow tables, which count both directions of a
+//! flow; each record is reported from the client source to the flow's destination. On the connect
+//! side that destination is the relay's own --udp-bind address, exported as 0.0.0.0 when bound to
+//! the wildcard address.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, SystemTime};
+
+/// Records per export datagram, the most a v5 collector accepts.
+pub const MAX_RECORDS: usize = 30;
+
+/// Long-lived flows are exported at least this often, each record covering the traffic since the
+/// previous one, so collectors see them before they end.
+pub const ACTIVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const HEADER_LEN: usize = 24;
+const RECORD_LEN: usize = 48;
+const PROTO_UDP: u8 = 17;
+
+/// One v5 flow record, with times in milliseconds of exporter uptime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowRecord {
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub packets: u32,
+    pub bytes: u32,
+    pub first: u32,
+    pub last: u32,
+}
+
+/// Encodes one export datagram. `records` must hold at most MAX_RECORDS entries; `sequence` is
+/// the total number of records sent before this datagram.
+pub fn encode_v5(records: &[FlowRecord], sys_uptime: u32, unix_time: Duration, sequence: u32) -> Vec<u8> {
+    debug_assert!(records.len() <= MAX_RECORDS);
+    let mut result = Vec::with_capacity(HEADER_LEN + records.len() * RECORD_LEN);
+    result.extend_from_slice(&5u16.to_be_bytes()); // version
+    result.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    result.extend_from_slice(&sys_uptime.to_be_bytes());
+    result.extend_from_slice(&(unix_time.as_secs() as u32).to_be_bytes());
+    result.extend_from_slice(&unix_time.subsec_nanos().to_be_bytes());
+    result.extend_from_slice(&sequence.to_be_bytes());
+    result.extend_from_slice(&[0, 0]); // engine type and id
+    result.extend_from_slice(&0u16.to_be_bytes()); // sampling interval: every packet counted
+
+    for record in records {
+        result.extend_from_slice(&record.src.ip().octets());
+        result.extend_from_slice(&record.dst.ip().octets());
+        result.extend_from_slice(&[0; 4]); // next hop
+        result.extend_from_slice(&[0; 4]); // input and output interface
+        result.extend_from_slice(&record.packets.to_be_bytes());
+        result.extend_from_slice(&record.bytes.to_be_bytes());
+        result.extend_from_slice(&record.first.to_be_bytes());
+        result.extend_from_slice(&record.last.to_be_bytes());
+        result.extend_from_slice(&record.src.port().to_be_bytes());
+        result.extend_from_slice(&record.dst.port().to_be_bytes());
+        result.extend_from_slice(&[0, 0, PROTO_UDP, 0]); // pad, TCP flags, protocol, ToS
+        result.extend_from_slice(&[0; 8]); // source/destination AS and mask, pad
+    }
+    result
+}
+
+/// IPv4 form of an address, if v5 can carry it.
+fn to_v4(addr: SocketAddr) -> Option<SocketAddrV4> {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => ip.to_ipv4_mapped()?,
+    };
+    Some(SocketAddrV4::new(ip, addr.port()))
+}
+
+/// Flow as last reported to the collector.
+struct ExportedFlow {
+    dst: SocketAddr,
+    /// Start of the traffic not yet exported: flow start, or the end of the previous record
+    since: SystemTime,
+    packets: u64,
+    bytes: u64,
+}
+
+/// Turns the relay's per-flow totals into v5 records and sends them to the collector. Sending is
+/// best effort: the socket is non-blocking and a failed datagram is logged and dropped.
+pub struct Exporter {
+    socket: std::net::UdpSocket,
+    collector: SocketAddr,
+    started: SystemTime,
+    sequence: u32,
+    flows: HashMap<SocketAddr, ExportedFlow>,
+    pending: Vec<FlowRecord>,
+}
+
+impl Exporter {
+    pub fn new(collector: SocketAddr) -> std::io::Result<Self> {
+        let bind_ip = match collector {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = std::net::UdpSocket::bind(SocketAddr::new(bind_ip, 0))?;
+        socket.set_nonblocking(true)?;
+        Ok(Exporter {
+            socket,
+            collector,
+            started: SystemTime::now(),
+            sequence: 0,
+            flows: HashMap::new(),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Starts tracking a flow on its first datagram.
+    pub fn flow_started(&mut self, src: SocketAddr, dst: SocketAddr) {
+        let now = SystemTime::now();
+        self.flows.entry(src).or_insert(ExportedFlow { dst, since: now, packets: 0, bytes: 0 });
+    }
+
+    /// Queues a record for the traffic of a flow that ended, given its totals and last activity.
+    pub fn flow_ended(&mut self, src: SocketAddr, packets: u64, bytes: u64, last: SystemTime) {
+        if let Some(flow) = self.flows.remove(&src) {
+            let record = self.record(src, &flow, packets, bytes, last);
+            self.pending.extend(record);
+        }
+    }
+
+    /// Queues a record for a flow that has been running for ACTIVE_TIMEOUT since it was last exported.
+    pub fn flow_active(&mut self, src: SocketAddr, packets: u64, bytes: u64, last: SystemTime) {
+        let Some(flow) = self.flows.get(&src) else {
+            return;
+        };
+        if last.duration_since(flow.since).map_or(true, |running| running < ACTIVE_TIMEOUT) {
+            return;
+        }
+        let record = self.record(src, flow, packets, bytes, last);
+        self.pending.extend(record);
+        if let Some(flow) = self.flows.get_mut(&src) {
+            flow.since = last;
+            flow.packets = packets;
+            flow.bytes = bytes;
+        }
+    }
+
+    /// Record for the traffic of `flow` since its last export, if there was any and v5 can carry it.
+    fn record(&self, src: SocketAddr, flow: &ExportedFlow, packets: u64, bytes: u64, last: SystemTime) -> Option<FlowRecord> {
+        let packets = packets.saturating_sub(flow.packets);
+        if packets == 0 {
+            return None;
+        }
+        let (Some(src_v4), Some(dst_v4)) = (to_v4(src), to_v4(flow.dst)) else {
+            tracing::trace!("not exporting flow {src} -> {}: NetFlow v5 only carries IPv4", flow.dst);
+            return None;
+        };
+        let uptime_ms = |t: SystemTime| t.duration_since(self.started).unwrap_or_default().as_millis() as u32;
+        Some(FlowRecord {
+            src: src_v4,
+            dst: dst_v4,
+            packets: packets.min(u64::from(u32::MAX)) as u32,
+            bytes: bytes.saturating_sub(flow.bytes).min(u64::from(u32::MAX)) as u32,
+            first: uptime_ms(flow.since),
+            last: uptime_ms(last),
+        })
+    }
+
+    /// Sends the queued records, MAX_RECORDS per datagram.
+    pub fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let now = SystemTime::now();
+        let sys_uptime = now.duration_since(self.started).unwrap_or_default().as_millis() as u32;
+        let unix_time = now.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        for records in self.pending.chunks(MAX_RECORDS) {
+            let datagram = encode_v5(records, sys_uptime, unix_time, self.sequence);
+            self.sequence = self.sequence.wrapping_add(records.len() as u32);
+            if let Err(e) = self.socket.send_to(&datagram, self.collector) {
+                tracing::warn!("dropping {} NetFlow records for {}: {}", records.len(), self.collector, e);
+            }
+        }
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    /// Exporter whose collector is a local socket the test reads the datagrams from.
+    fn exporter() -> (Exporter, std::net::UdpSocket) {
+        let collector = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let exporter = Exporter::new(collector.local_addr().unwrap()).unwrap();
+        (exporter, collector)
+    }
+
+    #[test]
+    fn encode_v5_layout() {
+        let record = FlowRecord {
+            src: "10.0.0.1:5000".parse().unwrap(),
+            dst: "192.168.1.2:53".parse().unwrap(),
+            packets: 3,
+            bytes: 300,
+            first: 1000,
+            last: 2500,
+        };
+        let unix_time = Duration::new(1_700_000_000, 42);
+        let datagram = encode_v5(&[record.clone(), record], 3000, unix_time, 7);
+        assert_eq!(datagram.len(), HEADER_LEN + 2 * RECORD_LEN);
+        assert_eq!(HEADER_LEN, 24);
+        assert_eq!(RECORD_LEN, 48);
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&[0, 5, 0, 2]); // version, count
+        header.extend_from_slice(&3000u32.to_be_bytes());
+        header.extend_from_slice(&1_700_000_000u32.to_be_bytes());
+        header.extend_from_slice(&42u32.to_be_bytes());
+        header.extend_from_slice(&7u32.to_be_bytes());
+        header.extend_from_slice(&[0; 4]);
+        assert_eq!(&datagram[..HEADER_LEN], &header[..]);
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&[10, 0, 0, 1, 192, 168, 1, 2]);
+        expected.extend_from_slice(&[0; 8]);
+        expected.extend_from_slice(&3u32.to_be_bytes());
+        expected.extend_from_slice(&300u32.to_be_bytes());
+        expected.extend_from_slice(&1000u32.to_be_bytes());
+        expected.extend_from_slice(&2500u32.to_be_bytes());
+        expected.extend_from_slice(&5000u16.to_be_bytes());
+        expected.extend_from_slice(&53u16.to_be_bytes());
+        expected.extend_from_slice(&[0, 0, PROTO_UDP, 0]);
+        expected.extend_from_slice(&[0; 8]);
+        for record in datagram[HEADER_LEN..].chunks(RECORD_LEN) {
+            assert_eq!(record, &expected[..]);
+        }
+    }
+
+    #[test]
+    fn ended_flow_is_sent_to_the_collector() {
+        let (mut exporter, collector) = exporter();
+        let src = v4("10.0.0.1:5000");
+        exporter.flow_started(src, v4("192.168.1.2:53"));
+        exporter.flow_started("[::ffff:10.0.0.2]:6000".parse().unwrap(), v4("192.168.1.2:53"));
+        exporter.flow_ended(src, 4, 400, SystemTime::now());
+        exporter.flow_ended("[::ffff:10.0.0.2]:6000".parse().unwrap(), 1, 100, SystemTime::now());
+        exporter.flush();
+
+        let mut buf = [0; 2048];
+        let len = collector.recv(&mut buf).unwrap();
+        assert_eq!(len, HEADER_LEN + 2 * RECORD_LEN);
+        assert_eq!(&buf[2..4], &[0, 2]);
+        // IPv4-mapped sources are exported as plain IPv4
+        assert_eq!(&buf[HEADER_LEN + RECORD_LEN..][..4], &[10, 0, 0, 2]);
+        assert!(exporter.flows.is_empty());
+    }
+
+    #[test]
+    fn ipv6_flows_are_skipped() {
+        let (mut exporter, _collector) = exporter();
+        let now = SystemTime::now();
+        exporter.flow_started("[2001:db8::1]:5000".parse().unwrap(), v4("192.168.1.2:53"));
+        exporter.flow_started(v4("10.0.0.1:5000"), "[2001:db8::2]:53".parse().unwrap());
+        exporter.flow_ended("[2001:db8::1]:5000".parse().unwrap(), 5, 500, now);
+        exporter.flow_ended(v4("10.0.0.1:5000"), 5, 500, now);
+        assert!(exporter.pending.is_empty());
+        assert!(exporter.flows.is_empty());
+    }
+
+    #[test]
+    fn active_flow_reports_deltas_after_timeout() {
+        let (mut exporter, _collector) = exporter();
+        let src = v4("10.0.0.1:5000");
+        exporter.flow_started(src, v4("192.168.1.2:53"));
+        let start = exporter.flows[&src].since;
+
+        // Not running for ACTIVE_TIMEOUT yet
+        exporter.flow_active(src, 10, 1000, start + ACTIVE_TIMEOUT - Duration::from_millis(1));
+        assert!(exporter.pending.is_empty());
+
+        let first_export = start + ACTIVE_TIMEOUT;
+        exporter.flow_active(src, 10, 1000, first_export);
+        assert_eq!(exporter.pending.len(), 1);
+        assert_eq!((exporter.pending[0].packets, exporter.pending[0].bytes), (10, 1000));
+
+        // The next export waits another ACTIVE_TIMEOUT and only covers the traffic since the first
+        exporter.flow_active(src, 15, 1500, first_export + ACTIVE_TIMEOUT / 2);
+        assert_eq!(exporter.pending.len(), 1);
+        let second_export = first_export + ACTIVE_TIMEOUT;
+        exporter.flow_active(src, 15, 1500, second_export);
+        assert_eq!(exporter.pending.len(), 2);
+        let record = &exporter.pending[1];
+        assert_eq!((record.packets, record.bytes), (5, 500));
+        let uptime_ms = |t: SystemTime| t.duration_since(exporter.started).unwrap().as_millis() as u32;
+        assert_eq!((record.first, record.last), (uptime_ms(first_export), uptime_ms(second_export)));
+
+        // The end record only carries what is left
+        exporter.flow_ended(src, 16, 1600, second_export + Duration::from_secs(1));
+        assert_eq!(exporter.pending.len(), 3);
+        assert_eq!((exporter.pending[2].packets, exporter.pending[2].bytes), (1, 100));
+    }
+}
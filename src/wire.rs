@@ -0,0 +1,612 @@
+//! Wire format of the TCP stream between the two tunnel endpoints.
+//!
+//! The stream is a sequence of frames, each a [`FrameHeader`] followed by `len` body bytes.
+//! Three framings exist, agreed per connection in the Hello exchange (see [`FrameFormat`]):
+//!
+//! - legacy: `[len:4][body:len]`, control frames marked by [`CONTROL_FRAME_FLAG`] in `len`
+//! - typed: `[len:4][type:1][body:len]`, with the type one of the `FRAME_TYPE_*` values
+//! - short typed: `[len:2][type:1][body:len]`, typed frames with a 16-bit length
+//!
+//! All integers are little-endian. Data frame bodies are a [`UdpPacketWithSource`], whose source
+//! header layout is given by the negotiated [`AddrFormat`]. Control frame bodies are a
+//! [`ControlFrame`].
+//!
+//! Revisions of the format, each gated by a `HELLO_*` capability bit so that older peers keep
+//! working with the original revision:
+//!
+//! 1. legacy framing, 18-byte source header, FlowPort and Hello control frames
+//! 2. compact 6-byte IPv4 source header ([`HELLO_IPV4_ONLY`])
+//! 3. typed frames ([`HELLO_TYPED_FRAMES`])
+//! 4. data frames without a source header ([`HELLO_NO_SOURCE_HEADER`])
+//! 5. client token in the Hello ([`HELLO_CLIENT_TOKEN`])
+//! 6. short typed frames ([`HELLO_SHORT_LENGTH`])
+//! 7. keepalive and keepalive echo control frames ([`HELLO_KEEPALIVE_ECHO`])
+//! 8. flow close control frames ([`HELLO_FLOW_CLOSE`])
+//! 9. goodbye control frames ([`HELLO_GOODBYE`])
+//! 10. flow port reports only to peers that ask for them ([`HELLO_FLOW_DIAGNOSTICS`])
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// Why a frame, or a part of one, could not be encoded or decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    /// Fewer bytes than the part needs. For a frame at the end of the receive buffer this only
+    /// means the rest has not arrived yet.
+    Truncated {
+        what: &'static str,
+        needed: usize,
+        available: usize,
+    },
+    /// Control frame kind this version does not know.
+    UnknownControlKind(u8),
+    /// Source address that the negotiated address format cannot carry.
+    UnrepresentableSource(SocketAddr, AddrFormat),
+    /// Frame body longer than the length field of the negotiated framing can express.
+    Oversized { len: usize, max: usize },
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::Truncated { what, needed, available } => {
+                write!(f, "truncated {what}: need {needed} bytes, have {available}")
+            }
+            WireError::UnknownControlKind(kind) => write!(f, "unknown control frame kind {kind}"),
+            WireError::UnrepresentableSource(source, format) => {
+                write!(f, "source {source} cannot be sent in the {format:?} address format")
+            }
+            WireError::Oversized { len, max } => write!(f, "frame body of {len} bytes exceeds the {max} byte limit of the framing"),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+/// Returns a Truncated error unless `data` holds at least `needed` bytes.
+fn ensure_len(what: &'static str, data: &[u8], needed: usize) -> Result<(), WireError> {
+    if data.len() < needed {
+        return Err(WireError::Truncated { what, needed, available: data.len() });
+    }
+    Ok(())
+}
+
+/// Hello capability bit: the endpoint runs with --ipv4-only and accepts the compact address format.
+pub const HELLO_IPV4_ONLY: u32 = 1 << 0;
+
+/// Hello capability bit: the endpoint understands typed frames. Every Hello reply carries it;
+/// an endpoint only announces it proactively with --typed-frames.
+pub const HELLO_TYPED_FRAMES: u32 = 1 << 1;
+
+/// Hello capability bit: the endpoint runs with --no-source-header and accepts header-less data frames.
+pub const HELLO_NO_SOURCE_HEADER: u32 = 1 << 2;
+
+/// Hello capability bit: the connect side sends a --client-token, so it initiates the handshake.
+pub const HELLO_CLIENT_TOKEN: u32 = 1 << 3;
+
+/// Hello capability bit: the endpoint runs with --frame-len-width 16 and accepts typed frames with
+/// a 16-bit length. Only used together with HELLO_TYPED_FRAMES.
+pub const HELLO_SHORT_LENGTH: u32 = 1 << 4;
+
+/// Hello capability bit: the endpoint answers Keepalive control frames with a KeepaliveEcho.
+/// Every Hello reply carries it; keepalives are only sent to peers that announced it.
+pub const HELLO_KEEPALIVE_ECHO: u32 = 1 << 5;
+
+/// Hello capability bit: the endpoint removes a flow when the peer sends a FlowClose for it.
+/// Every Hello reply carries it; FlowClose is only sent to peers that announced it.
+pub const HELLO_FLOW_CLOSE: u32 = 1 << 6;
+
+/// Hello capability bit: the endpoint understands a Goodbye sent ahead of a deliberate close.
+/// Every Hello reply carries it; Goodbye is only sent to peers that announced it.
+pub const HELLO_GOODBYE: u32 = 1 << 7;
+
+/// Hello capability bit: the endpoint reads FlowPort control frames (--flow-diagnostics).
+/// Every Hello carries it; FlowPort is only sent to peers that announced it.
+pub const HELLO_FLOW_DIAGNOSTICS: u32 = 1 << 8;
+
+/// Control frames share the TCP stream with data frames. In legacy framing they are marked by the
+/// high bit of the length prefix, which a data frame never sets since UDP datagrams are far
+/// smaller than 2 GiB.
+pub const CONTROL_FRAME_FLAG: u32 = 0x8000_0000;
+
+/// Frame types carried in the type byte of typed frames. Legacy framing can only express data
+/// and control frames, via the high bit of the length prefix. Type 1 is unused; keepalives are
+/// sent as [`ControlFrame::Keepalive`] in every framing.
+pub const FRAME_TYPE_DATA: u8 = 0;
+pub const FRAME_TYPE_CONTROL: u8 = 2;
+
+/// Framing of the TCP stream, agreed per connection via the Hello exchange.
+/// The Hello frames themselves always use legacy framing; the negotiated framing applies to
+/// everything each endpoint sends after its Hello.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameFormat {
+    /// [len:4][body:N] with control frames marked by CONTROL_FRAME_FLAG (default)
+    Legacy,
+    /// [len:4][type:1][body:N], used when both endpoints support typed frames (--typed-frames)
+    Typed,
+    /// [len:2][type:1][body:N], typed frames when both endpoints run with --frame-len-width 16
+    TypedShort,
+}
+
+impl FrameFormat {
+    /// Typed frames are used when both Hellos carry HELLO_TYPED_FRAMES, with a 16-bit length
+    /// when both also carry HELLO_SHORT_LENGTH.
+    pub fn negotiate(local_flags: u32, peer_flags: u32) -> Self {
+        let common = local_flags & peer_flags;
+        if common & HELLO_TYPED_FRAMES == 0 {
+            FrameFormat::Legacy
+        } else if common & HELLO_SHORT_LENGTH != 0 {
+            FrameFormat::TypedShort
+        } else {
+            FrameFormat::Typed
+        }
+    }
+
+    /// Number of header bytes preceding the frame body.
+    pub fn header_len(self) -> usize {
+        match self {
+            FrameFormat::Legacy => 4,
+            FrameFormat::Typed => 5,
+            FrameFormat::TypedShort => 3,
+        }
+    }
+
+    /// Largest body the length field can express. Legacy framing loses the high bit to CONTROL_FRAME_FLAG.
+    pub fn max_body_len(self) -> usize {
+        match self {
+            FrameFormat::Legacy => (CONTROL_FRAME_FLAG - 1) as usize,
+            FrameFormat::Typed => u32::MAX as usize,
+            FrameFormat::TypedShort => u16::MAX as usize,
+        }
+    }
+}
+
+/// Header preceding every frame body: its type and the body length in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub frame_type: u8,
+    pub len: usize,
+}
+
+impl FrameHeader {
+    pub fn new(frame_type: u8, len: usize) -> Self {
+        FrameHeader { frame_type, len }
+    }
+
+    /// Appends the encoded header. In legacy framing every non-data type is sent as a control frame.
+    /// Fails with Oversized, appending nothing, if the length does not fit the framing.
+    pub fn encode(&self, format: FrameFormat, out: &mut Vec<u8>) -> Result<(), WireError> {
+        if self.len > format.max_body_len() {
+            return Err(WireError::Oversized { len: self.len, max: format.max_body_len() });
+        }
+        match format {
+            FrameFormat::Legacy => {
+                let flag = if self.frame_type == FRAME_TYPE_DATA { 0 } else { CONTROL_FRAME_FLAG };
+                out.extend_from_slice(&(self.len as u32 | flag).to_le_bytes());
+            }
+            FrameFormat::Typed => {
+                out.extend_from_slice(&(self.len as u32).to_le_bytes());
+                out.push(self.frame_type);
+            }
+            FrameFormat::TypedShort => {
+                out.extend_from_slice(&(self.len as u16).to_le_bytes());
+                out.push(self.frame_type);
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the header at the start of `data`.
+    pub fn decode(format: FrameFormat, data: &[u8]) -> Result<Self, WireError> {
+        ensure_len("frame header", data, format.header_len())?;
+        if format == FrameFormat::TypedShort {
+            return Ok(FrameHeader::new(data[2], u16::from_le_bytes([data[0], data[1]]).into()));
+        }
+        let raw_len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        let (frame_type, len) = match format {
+            FrameFormat::Legacy if raw_len & CONTROL_FRAME_FLAG != 0 => (FRAME_TYPE_CONTROL, raw_len & !CONTROL_FRAME_FLAG),
+            FrameFormat::Legacy => (FRAME_TYPE_DATA, raw_len),
+            FrameFormat::Typed | FrameFormat::TypedShort => (data[4], raw_len),
+        };
+        Ok(FrameHeader::new(frame_type, len as usize))
+    }
+}
+
+/// Appends a whole frame (header and body) to `out`, or nothing if the body is too long for the framing.
+pub fn encode_frame(format: FrameFormat, frame_type: u8, body: &[u8], out: &mut Vec<u8>) -> Result<(), WireError> {
+    FrameHeader::new(frame_type, body.len()).encode(format, out)?;
+    out.extend_from_slice(body);
+    Ok(())
+}
+
+/// Splits the frame at the start of `data` into its header, its body and the bytes following it.
+/// Truncated is the only possible error, meaning the frame is not complete yet.
+pub fn split_frame(format: FrameFormat, data: &[u8]) -> Result<(FrameHeader, &[u8], &[u8]), WireError> {
+    let header = FrameHeader::decode(format, data)?;
+    let end = format.header_len() + header.len;
+    ensure_len("frame body", data, end)?;
+    Ok((header, &data[format.header_len()..end], &data[end..]))
+}
+
+/// UDP packet structure that preserves original source address information
+/// when tunneling through TCP connections. This enables proper return packet routing.
+#[derive(Debug, Clone)]
+pub struct UdpPacketWithSource {
+    pub source: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+impl UdpPacketWithSource {
+    /// Serializes the UDP packet with source address metadata for TCP transmission.
+    /// Format: [port:2][ip:16][data:N] where IP is always 16 bytes (IPv4 mapped to IPv6),
+    /// or [port:2][ipv4:4][data:N] when the compact IPv4 format was negotiated.
+    /// With the Omitted format the frame body is the raw payload.
+    pub fn serialize(&self, format: AddrFormat) -> Result<Vec<u8>, WireError> {
+        let mut result = Vec::with_capacity(format.header_len() + self.data.len());
+
+        match format {
+            // Source address as [port:2][ip:16]
+            AddrFormat::Full => encode_addr(&self.source, &mut result),
+            // Source address as [port:2][ipv4:4]
+            AddrFormat::Ipv4 => {
+                let ipv4 = match self.source.ip() {
+                    IpAddr::V4(ipv4) => ipv4,
+                    IpAddr::V6(ipv6) => ipv6
+                        .to_ipv4_mapped()
+                        .ok_or(WireError::UnrepresentableSource(self.source, format))?,
+                };
+                result.extend_from_slice(&self.source.port().to_le_bytes());
+                result.extend_from_slice(&ipv4.octets());
+            }
+            AddrFormat::Omitted => {}
+        }
+
+        // Original UDP packet payload
+        result.extend_from_slice(&self.data);
+        Ok(result)
+    }
+
+    /// Deserializes a UDP packet with source address metadata from TCP stream.
+    /// Fails if the data is shorter than the source header of the given format. A body of exactly
+    /// the header length is a valid zero-length datagram and yields empty `data`.
+    pub fn deserialize(data: &[u8], format: AddrFormat) -> Result<Self, WireError> {
+        let source = match format {
+            // Need at least 2 bytes port + 16 bytes IP
+            AddrFormat::Full => decode_addr(data)?,
+            // Need at least 2 bytes port + 4 bytes IPv4
+            AddrFormat::Ipv4 => {
+                ensure_len("IPv4 source header", data, AddrFormat::Ipv4.header_len())?;
+                let port = u16::from_le_bytes([data[0], data[1]]);
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(data[2], data[3], data[4], data[5])), port)
+            }
+            // No header: every packet belongs to the one static flow
+            AddrFormat::Omitted => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        };
+        let packet_data = data[format.header_len()..].to_vec(); // Remaining bytes are the UDP payload
+
+        Ok(UdpPacketWithSource {
+            source,
+            data: packet_data,
+        })
+    }
+}
+
+/// Encoding of the source address in data frames, agreed per connection via the Hello exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrFormat {
+    /// [port:2][ip:16] with IPv4 mapped to IPv6 (default)
+    Full,
+    /// [port:2][ipv4:4], used only when both endpoints run with --ipv4-only
+    Ipv4,
+    /// No source header, used only when both endpoints run with --no-source-header
+    Omitted,
+}
+
+impl AddrFormat {
+    /// Number of header bytes preceding the UDP payload in a data frame.
+    pub fn header_len(self) -> usize {
+        match self {
+            AddrFormat::Full => ENCODED_ADDR_LEN,
+            AddrFormat::Ipv4 => 6,
+            AddrFormat::Omitted => 0,
+        }
+    }
+}
+
+/// Encoded size of a socket address on the wire: [port:2][ip:16].
+pub const ENCODED_ADDR_LEN: usize = 18;
+
+/// Appends a socket address in wire format: [port:2][ip:16] where IP is always 16 bytes
+/// (IPv4 mapped to IPv6).
+fn encode_addr(addr: &SocketAddr, out: &mut Vec<u8>) {
+    // Port as little-endian 16-bit integer
+    out.extend_from_slice(&addr.port().to_le_bytes());
+
+    // IP address normalized to 16 bytes (IPv6 format)
+    match addr.ip() {
+        IpAddr::V4(ipv4) => {
+            // IPv4-mapped IPv6 format: ::ffff:a.b.c.d
+            out.extend_from_slice(&[0u8; 10]);     // 10 zero bytes
+            out.extend_from_slice(&[0xff, 0xff]);  // IPv4-mapped prefix
+            out.extend_from_slice(&ipv4.octets()); // 4 bytes of IPv4 address
+        }
+        IpAddr::V6(ipv6) => {
+            out.extend_from_slice(&ipv6.octets()); // Native 16-byte IPv6 address
+        }
+    }
+}
+
+/// Decodes a socket address written by `encode_addr` from the start of `data`.
+/// Fails if fewer than 18 bytes are available.
+fn decode_addr(data: &[u8]) -> Result<SocketAddr, WireError> {
+    ensure_len("socket address", data, ENCODED_ADDR_LEN)?;
+
+    // Extract port from first 2 bytes (little-endian)
+    let port = u16::from_le_bytes([data[0], data[1]]);
+
+    // Extract IP from next 16 bytes
+    let ip_bytes = &data[2..18];
+    let ip = if ip_bytes[0..10] == [0u8; 10] && ip_bytes[10..12] == [0xff, 0xff] {
+        // IPv4-mapped IPv6 format detected
+        IpAddr::V4(Ipv4Addr::new(ip_bytes[12], ip_bytes[13], ip_bytes[14], ip_bytes[15]))
+    } else {
+        // Native IPv6 address
+        let mut ipv6_bytes = [0u8; 16];
+        ipv6_bytes.copy_from_slice(ip_bytes);
+        IpAddr::V6(std::net::Ipv6Addr::from(ipv6_bytes))
+    };
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Non-data messages exchanged between the two tunnel endpoints.
+/// Format: [kind:1][body:N], where the body layout depends on the kind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlFrame {
+    /// Listen side -> connect side: local port assigned to a flow in auto mode (--flow-diagnostics).
+    /// Body: [source:18][flow_port:2][backend:18]
+    FlowPort {
+        source: SocketAddr,
+        flow_port: u16,
+        backend: SocketAddr,
+    },
+    /// Capability announcement exchanged when a connection is established.
+    /// Body: [flags:4][token_len:1][token:N] (see the HELLO_* bits); the token is the connect side's
+    /// --client-token and may be empty. Peers that only read the flags ignore the rest.
+    Hello { flags: u32, token: Vec<u8> },
+    /// Keepalive sent every --keepalive-interval, answered with a KeepaliveEcho carrying the same
+    /// sequence number so the sender can measure the round-trip time. Body: [seq:4]
+    Keepalive { seq: u32 },
+    /// Answer to a Keepalive. Body: [seq:4]
+    KeepaliveEcho { seq: u32 },
+    /// Connect side -> listen side: the client flow from `source` was cleaned up, so the listen
+    /// side can close its flow socket without waiting for its own idle timeout. Body: [source:18]
+    FlowClose { source: SocketAddr },
+    /// Sent right before an endpoint closes the connection because it is shutting down, so the
+    /// peer can tell the close from a broken connection. Body: empty
+    Goodbye,
+}
+
+impl ControlFrame {
+    const KIND_FLOW_PORT: u8 = 1;
+    const KIND_HELLO: u8 = 2;
+    const KIND_KEEPALIVE: u8 = 3;
+    const KIND_KEEPALIVE_ECHO: u8 = 4;
+    const KIND_FLOW_CLOSE: u8 = 5;
+    const KIND_GOODBYE: u8 = 6;
+
+    /// Serializes the control frame body (without the frame header).
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        match self {
+            ControlFrame::FlowPort { source, flow_port, backend } => {
+                result.push(Self::KIND_FLOW_PORT);
+                encode_addr(source, &mut result);
+                result.extend_from_slice(&flow_port.to_le_bytes());
+                encode_addr(backend, &mut result);
+            }
+            ControlFrame::Hello { flags, token } => {
+                result.push(Self::KIND_HELLO);
+                result.extend_from_slice(&flags.to_le_bytes());
+                result.push(token.len() as u8);
+                result.extend_from_slice(token);
+            }
+            ControlFrame::Keepalive { seq } => {
+                result.push(Self::KIND_KEEPALIVE);
+                result.extend_from_slice(&seq.to_le_bytes());
+            }
+            ControlFrame::KeepaliveEcho { seq } => {
+                result.push(Self::KIND_KEEPALIVE_ECHO);
+                result.extend_from_slice(&seq.to_le_bytes());
+            }
+            ControlFrame::FlowClose { source } => {
+                result.push(Self::KIND_FLOW_CLOSE);
+                encode_addr(source, &mut result);
+            }
+            ControlFrame::Goodbye => {
+                result.push(Self::KIND_GOODBYE);
+            }
+        }
+        result
+    }
+
+    /// Deserializes a control frame body.
+    /// Fails for unknown kinds and truncated bodies.
+    pub fn deserialize(data: &[u8]) -> Result<Self, WireError> {
+        ensure_len("control frame kind", data, 1)?;
+        let (kind, body) = (data[0], &data[1..]);
+        match kind {
+            Self::KIND_FLOW_PORT => {
+                let source = decode_addr(body)?;
+                let rest = &body[ENCODED_ADDR_LEN..];
+                ensure_len("flow port", rest, 2)?;
+                let flow_port = u16::from_le_bytes([rest[0], rest[1]]);
+                let backend = decode_addr(&rest[2..])?;
+                Ok(ControlFrame::FlowPort { source, flow_port, backend })
+            }
+            Self::KIND_HELLO => {
+                ensure_len("hello flags", body, 4)?;
+                let flags = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+                // Hellos without a token section come from peers that predate it
+                let token = match body[4..].split_first() {
+                    Some((&token_len, rest)) => {
+                        ensure_len("hello token", rest, token_len as usize)?;
+                        rest[..token_len as usize].to_vec()
+                    }
+                    None => Vec::new(),
+                };
+                Ok(ControlFrame::Hello { flags, token })
+            }
+            Self::KIND_KEEPALIVE | Self::KIND_KEEPALIVE_ECHO => {
+                ensure_len("keepalive sequence number", body, 4)?;
+                let seq = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+                if kind == Self::KIND_KEEPALIVE {
+                    Ok(ControlFrame::Keepalive { seq })
+                } else {
+                    Ok(ControlFrame::KeepaliveEcho { seq })
+                }
+            }
+            Self::KIND_FLOW_CLOSE => Ok(ControlFrame::FlowClose { source: decode_addr(body)? }),
+            Self::KIND_GOODBYE => Ok(ControlFrame::Goodbye),
+            _ => Err(WireError::UnknownControlKind(kind)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    const FRAME_FORMATS: [FrameFormat; 3] = [FrameFormat::Legacy, FrameFormat::Typed, FrameFormat::TypedShort];
+
+    #[test]
+    fn frame_header_round_trip() {
+        for format in FRAME_FORMATS {
+            for frame_type in [FRAME_TYPE_DATA, FRAME_TYPE_CONTROL] {
+                for len in [0, 1, 1500, format.max_body_len()] {
+                    let header = FrameHeader::new(frame_type, len);
+                    let mut encoded = Vec::new();
+                    header.encode(format, &mut encoded).unwrap();
+                    assert_eq!(encoded.len(), format.header_len());
+                    assert_eq!(FrameHeader::decode(format, &encoded), Ok(header), "{format:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn frame_round_trip_keeps_following_bytes() {
+        for format in FRAME_FORMATS {
+            let mut stream = Vec::new();
+            encode_frame(format, FRAME_TYPE_DATA, b"first", &mut stream).unwrap();
+            encode_frame(format, FRAME_TYPE_CONTROL, b"second", &mut stream).unwrap();
+            let (header, body, rest) = split_frame(format, &stream).unwrap();
+            assert_eq!((header.frame_type, body), (FRAME_TYPE_DATA, &b"first"[..]));
+            let (header, body, rest) = split_frame(format, rest).unwrap();
+            assert_eq!((header.frame_type, body), (FRAME_TYPE_CONTROL, &b"second"[..]));
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn header_only_data_frame_is_zero_length_datagram() {
+        let source = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 5000);
+        for format in FRAME_FORMATS {
+            for addr_format in [AddrFormat::Full, AddrFormat::Ipv4, AddrFormat::Omitted] {
+                let packet = UdpPacketWithSource { source, data: Vec::new() };
+                let serialized = packet.serialize(addr_format).unwrap();
+                assert_eq!(serialized.len(), addr_format.header_len());
+                let mut stream = Vec::new();
+                encode_frame(format, FRAME_TYPE_DATA, &serialized, &mut stream).unwrap();
+                let (header, body, rest) = split_frame(format, &stream).unwrap();
+                assert_eq!((header.frame_type, rest.len()), (FRAME_TYPE_DATA, 0));
+                let decoded = UdpPacketWithSource::deserialize(body, addr_format).unwrap();
+                assert!(decoded.data.is_empty(), "{format:?} {addr_format:?}");
+                if addr_format != AddrFormat::Omitted {
+                    assert_eq!(decoded.source, source);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn control_frame_round_trip() {
+        let v4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 5000);
+        let v6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)), 6000);
+        let frames = [
+            ControlFrame::FlowPort { source: v4, flow_port: 40000, backend: v6 },
+            ControlFrame::Hello { flags: HELLO_TYPED_FRAMES | HELLO_GOODBYE, token: Vec::new() },
+            ControlFrame::Hello { flags: 0, token: b"secret".to_vec() },
+            ControlFrame::Keepalive { seq: 7 },
+            ControlFrame::KeepaliveEcho { seq: u32::MAX },
+            ControlFrame::FlowClose { source: v6 },
+            ControlFrame::Goodbye,
+        ];
+        for format in FRAME_FORMATS {
+            for frame in &frames {
+                let mut stream = Vec::new();
+                encode_frame(format, FRAME_TYPE_CONTROL, &frame.serialize(), &mut stream).unwrap();
+                let (header, body, _) = split_frame(format, &stream).unwrap();
+                assert_eq!(header.frame_type, FRAME_TYPE_CONTROL);
+                assert_eq!(&ControlFrame::deserialize(body).unwrap(), frame, "{format:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn hello_without_token_section() {
+        let body = [ControlFrame::KIND_HELLO, 2, 0, 0, 0];
+        assert_eq!(ControlFrame::deserialize(&body), Ok(ControlFrame::Hello { flags: 2, token: Vec::new() }));
+    }
+
+    #[test]
+    fn truncated_header() {
+        for format in FRAME_FORMATS {
+            let err = FrameHeader::decode(format, &[0; 2]).unwrap_err();
+            assert_eq!(err, WireError::Truncated { what: "frame header", needed: format.header_len(), available: 2 });
+        }
+    }
+
+    #[test]
+    fn truncated_body() {
+        let mut stream = Vec::new();
+        encode_frame(FrameFormat::Typed, FRAME_TYPE_DATA, b"payload", &mut stream).unwrap();
+        stream.pop();
+        assert!(matches!(split_frame(FrameFormat::Typed, &stream), Err(WireError::Truncated { what: "frame body", .. })));
+    }
+
+    #[test]
+    fn unknown_frame_type_is_left_to_the_caller() {
+        let mut stream = Vec::new();
+        encode_frame(FrameFormat::Typed, 9, b"future", &mut stream).unwrap();
+        let (header, body, _) = split_frame(FrameFormat::Typed, &stream).unwrap();
+        assert_eq!((header.frame_type, body), (9, &b"future"[..]));
+    }
+
+    #[test]
+    fn unknown_control_kind() {
+        assert_eq!(ControlFrame::deserialize(&[200, 1, 2]), Err(WireError::UnknownControlKind(200)));
+        assert!(matches!(ControlFrame::deserialize(&[]), Err(WireError::Truncated { .. })));
+        assert!(matches!(ControlFrame::deserialize(&[ControlFrame::KIND_KEEPALIVE, 1]), Err(WireError::Truncated { .. })));
+    }
+
+    #[test]
+    fn unrepresentable_source() {
+        let source = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 5000);
+        let packet = UdpPacketWithSource { source, data: b"data".to_vec() };
+        assert_eq!(packet.serialize(AddrFormat::Ipv4).unwrap_err(), WireError::UnrepresentableSource(source, AddrFormat::Ipv4));
+        assert!(matches!(UdpPacketWithSource::deserialize(&[0; 10], AddrFormat::Full), Err(WireError::Truncated { .. })));
+    }
+
+    #[test]
+    fn oversized_length() {
+        for format in [FrameFormat::Legacy, FrameFormat::TypedShort] {
+            let mut out = Vec::new();
+            let len = format.max_body_len() + 1;
+            let err = FrameHeader::new(FRAME_TYPE_DATA, len).encode(format, &mut out).unwrap_err();
+            assert_eq!(err, WireError::Oversized { len, max: format.max_body_len() });
+            assert!(out.is_empty());
+        }
+    }
+}